@@ -0,0 +1,187 @@
+use std::fs;
+use std::str::FromStr;
+use alvarium_annotator::{AnnotationList, MessageWrapper, Publisher};
+use alvarium_sdk_rust::config::{DemiaStreamsConfig, StreamConfig, StreamInfo};
+use base64::Engine;
+use crypto::signatures::ed25519::SecretKey;
+use serde::{Deserialize, Serialize};
+use streams::{Address, User};
+use streams::id::Ed25519;
+use streams::transport::utangle::Client;
+use crate::codec::{encode_message, ContentEncoding, MessageKind, PayloadEncoding};
+use crate::config::PublisherConfig;
+use crate::errors::{Error, Result};
+use crate::pipeline::{AnnotationBundle, ReadingLink};
+
+lazy_static! {
+    /// Reading the sdk is about to annotate. The sdk only hands the annotations over to the publisher,
+    /// so the pipeline leaves the link to the reading here for the annotations to be sent with.
+    static ref NEXT_LINK: std::sync::Mutex<Option<ReadingLink>> = std::sync::Mutex::new(None);
+}
+
+/// Link the next annotations the sdk publishes to a reading
+pub fn link_next(link: ReadingLink) {
+    *NEXT_LINK.lock().unwrap() = Some(link);
+}
+
+/// Publisher the sdk hands the annotations of each reading to. Like the demia publisher of the sdk, it
+/// writes to the annotation topic with a stream user of its own, subscribed through the provider api and
+/// backed up to the configured path, but it sends the annotations in the same envelope, encoding and
+/// compression as every other message of the publisher, linked to the reading they were created for.
+pub struct AnnotationPublisher {
+    config: DemiaStreamsConfig,
+    user: User<Client>,
+    encoding: PayloadEncoding,
+    compression: ContentEncoding,
+}
+
+impl AnnotationPublisher {
+    async fn backup(&mut self) -> Result<()> {
+        let backup = self.user.backup(&self.config.backup.password).await
+            .map_err(|e| Error::StreamError(e.to_string()))?;
+        fs::write(&self.config.backup.path, backup).map_err(|e| Error::StreamError(e.to_string()))
+    }
+}
+
+#[async_trait::async_trait]
+impl Publisher for AnnotationPublisher {
+    type StreamConfig = StreamInfo;
+    type Error = alvarium_sdk_rust::errors::Error;
+
+    async fn new(cfg: &StreamInfo) -> core::result::Result<Self, Self::Error> {
+        let config = streams_config(cfg)?;
+        let user = annotation_user(&config).await?;
+        let publisher_config = PublisherConfig::load();
+        Ok(AnnotationPublisher {
+            config,
+            user,
+            encoding: publisher_config.encoding,
+            compression: publisher_config.compression,
+        })
+    }
+
+    async fn close(&mut self) -> core::result::Result<(), Self::Error> {
+        Ok(self.backup().await?)
+    }
+
+    /// Subscribe to the annotation topic through the provider api, which answers once the keyload
+    /// granting access to it was sent
+    async fn connect(&mut self) -> core::result::Result<(), Self::Error> {
+        if self.user.stream_address().is_none() {
+            let announcement = fetch_announcement(&self.config.provider.uri()).await?;
+            self.user.receive_message(announcement).await.map_err(|e| Error::StreamError(e.to_string()))?;
+        }
+
+        let subscription = self.user.subscribe().await.map_err(|e| Error::StreamError(e.to_string()))?;
+        let request = SubscriptionRequest {
+            address: subscription.address().to_string(),
+            identifier: self.user.identifier().map(|id| id.to_string()).unwrap_or_default(),
+            id_type: 0,
+            topic: self.config.topic.clone(),
+        };
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscribe", self.config.provider.uri()))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request).map_err(|e| Error::SerialisationError(e.to_string()))?)
+            .send()
+            .await
+            .map_err(|e| Error::StreamError(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::StreamError(format!("Subscription refused: {}", response.status())).into())
+        }
+
+        self.user.sync().await.map_err(|e| Error::StreamError(e.to_string()))?;
+        log::info!("Annotation publisher subscribed to {}", self.config.topic);
+        Ok(self.backup().await?)
+    }
+
+    async fn reconnect(&mut self) -> core::result::Result<(), Self::Error> {
+        self.connect().await
+    }
+
+    /// Send the annotation list of a reading on the annotation topic, enveloped like every other message
+    /// along with the link to the reading
+    async fn publish(&mut self, msg: MessageWrapper<'_>) -> core::result::Result<(), Self::Error> {
+        let reading = NEXT_LINK.lock().unwrap().take()
+            .ok_or_else(|| Error::StreamError("no reading to link the annotations to".to_string()))?;
+        let content = base64::engine::general_purpose::STANDARD.decode(msg.content)
+            .map_err(|e| Error::SerialisationError(e.to_string()))?;
+        let annotations = serde_json::from_slice::<AnnotationList>(&content)
+            .map_err(|e| Error::SerialisationError(e.to_string()))?;
+        let bundle = AnnotationBundle { reading, annotations: annotations.items };
+        let payload = encode_message(MessageKind::Annotations, &bundle, self.encoding, self.compression)?;
+
+        let sent = self.user.message()
+            .with_topic(self.config.topic.as_str())
+            .with_payload(payload.as_slice())
+            .signed()
+            .send()
+            .await
+            .map_err(|e| Error::StreamError(e.to_string()))?;
+        log::debug!("Annotations sent: {}", sent.address());
+        Ok(self.backup().await?)
+    }
+}
+
+/// Subscription request as the provider api expects it
+#[derive(Serialize)]
+struct SubscriptionRequest {
+    address: String,
+    identifier: String,
+    #[serde(rename = "idType")]
+    id_type: u8,
+    topic: String,
+}
+
+fn streams_config(cfg: &StreamInfo) -> Result<DemiaStreamsConfig> {
+    match &cfg.config {
+        StreamConfig::DemiaStreams(config) => Ok(config.clone()),
+        _ => Err(Error::StreamError("the annotation publisher needs a DemiaStreams configuration".to_string())),
+    }
+}
+
+/// Identifier of the stream user the sdk publishes annotations with, so that it can be enrolled as the
+/// annotation writer before the sdk subscribes
+pub async fn writer_identifier(cfg: &StreamInfo) -> Result<String> {
+    let user = annotation_user(&streams_config(cfg)?).await?;
+    user.identifier()
+        .map(|id| id.to_string())
+        .ok_or_else(|| Error::StreamError("the annotation publisher user has no identity".to_string()))
+}
+
+/// Stream user of the annotation publisher, restored from its backup or created and backed up at once
+/// so that its identity stays the same across restarts
+async fn annotation_user(config: &DemiaStreamsConfig) -> Result<User<Client>> {
+    let client = Client::new(&config.tangle_node.uri());
+    if let Ok(bytes) = fs::read(&config.backup.path) {
+        return User::restore(bytes, &config.backup.password, client).await
+            .map_err(|e| Error::StreamError(e.to_string()))
+    }
+
+    let key = SecretKey::generate().map_err(|e| Error::StreamError(e.to_string()))?;
+    let mut user = User::builder()
+        .with_transport(client)
+        .with_identity(Ed25519::new(key))
+        .lean()
+        .build();
+    let backup = user.backup(&config.backup.password).await.map_err(|e| Error::StreamError(e.to_string()))?;
+    fs::write(&config.backup.path, backup).map_err(|e| Error::StreamError(e.to_string()))?;
+    Ok(user)
+}
+
+/// Announcement of the stream, as returned by the provider api
+async fn fetch_announcement(provider: &str) -> Result<Address> {
+    #[derive(Deserialize)]
+    struct AnnouncementResponse {
+        announcement_id: String,
+    }
+
+    let response = reqwest::get(format!("{}/get_announcement_id", provider)).await
+        .map_err(|e| Error::StreamError(e.to_string()))?
+        .bytes()
+        .await
+        .map_err(|e| Error::StreamError(e.to_string()))?;
+    let announcement = serde_json::from_slice::<AnnouncementResponse>(&response)
+        .map_err(|e| Error::SerialisationError(e.to_string()))?;
+    Address::from_str(&announcement.announcement_id).map_err(|e| Error::StreamError(e.to_string()))
+}
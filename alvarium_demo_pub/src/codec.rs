@@ -0,0 +1,125 @@
+use std::io::Write;
+use serde::{Deserialize, Serialize};
+use crate::errors::{Error, Result};
+
+/// Prefix of every payload sent by the publisher. It is followed by a byte identifying the
+/// `PayloadEncoding` of the envelope and a byte identifying the `ContentEncoding` applied to it.
+/// Legacy payloads are plain json and never start with it.
+pub const FRAME_MARKER: &'static [u8] = b"ALV";
+
+/// Compression applied to stream message payloads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    #[default]
+    Identity,
+    Deflate,
+    Zstd,
+}
+
+impl ContentEncoding {
+    fn marker(&self) -> u8 {
+        match self {
+            ContentEncoding::Identity => 0,
+            ContentEncoding::Deflate => 1,
+            ContentEncoding::Zstd => 2,
+        }
+    }
+}
+
+/// Serialisation format of the message envelope
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl PayloadEncoding {
+    fn marker(&self) -> u8 {
+        match self {
+            PayloadEncoding::Json => 0,
+            PayloadEncoding::Cbor => 1,
+            PayloadEncoding::MessagePack => 2,
+        }
+    }
+}
+
+/// Version of the envelope layout, bumped whenever the meaning of its fields changes
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Type of the content carried by an envelope
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Reading,
+    ReadingBatch,
+    Heartbeat,
+    SensorKeys,
+    Annotations,
+}
+
+impl MessageKind {
+    /// Identifier of the body schema, versioned independently for each message type
+    pub fn schema(&self) -> &'static str {
+        match self {
+            MessageKind::Reading => "alvarium.demo.signed_reading.v1",
+            MessageKind::ReadingBatch => "alvarium.demo.reading_batch.v3",
+            MessageKind::Heartbeat => "alvarium.demo.heartbeat.v1",
+            MessageKind::SensorKeys => "alvarium.demo.sensor_key_registry.v1",
+            MessageKind::Annotations => "alvarium.demo.annotation_bundle.v1",
+        }
+    }
+}
+
+/// Tags a message body with its type, envelope version and body schema so the subscriber can
+/// dispatch it without guessing
+#[derive(Serialize)]
+pub struct Envelope<'a, T: Serialize> {
+    #[serde(rename = "type")]
+    pub kind: MessageKind,
+    pub version: u8,
+    pub schema: &'static str,
+    pub body: &'a T,
+}
+
+/// Wrap a body in an envelope, serialise and compress it, and prefix it with the frame header
+pub fn encode_message<T: Serialize>(
+    kind: MessageKind,
+    body: &T,
+    encoding: PayloadEncoding,
+    compression: ContentEncoding,
+) -> Result<Vec<u8>> {
+    let envelope = Envelope { kind, version: ENVELOPE_VERSION, schema: kind.schema(), body };
+    let serialised = match encoding {
+        PayloadEncoding::Json => serde_json::to_vec(&envelope)
+            .map_err(|e| Error::SerialisationError(e.to_string()))?,
+        PayloadEncoding::Cbor => {
+            let mut serialised = Vec::new();
+            ciborium::ser::into_writer(&envelope, &mut serialised)
+                .map_err(|e| Error::SerialisationError(e.to_string()))?;
+            serialised
+        },
+        // Named so that fields can be looked up by name like in the other encodings
+        PayloadEncoding::MessagePack => rmp_serde::to_vec_named(&envelope)
+            .map_err(|e| Error::SerialisationError(e.to_string()))?,
+    };
+
+    let compressed = match compression {
+        ContentEncoding::Identity => serialised,
+        ContentEncoding::Deflate => {
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&serialised).map_err(Error::CompressionError)?;
+            encoder.finish().map_err(Error::CompressionError)?
+        },
+        ContentEncoding::Zstd => zstd::encode_all(serialised.as_slice(), 0).map_err(Error::CompressionError)?,
+    };
+
+    let mut encoded = FRAME_MARKER.to_vec();
+    encoded.push(encoding.marker());
+    encoded.push(compression.marker());
+    encoded.extend(compressed);
+    Ok(encoded)
+}
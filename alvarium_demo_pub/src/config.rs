@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+use crate::codec::{ContentEncoding, PayloadEncoding};
+
+/// Path of the publisher specific configuration, kept apart from the sdk configuration
+pub const PUBLISHER_CONFIG_PATH: &'static str = "config/publisher.json";
+
+/// Options of the demo publisher that are not part of the sdk configuration
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PublisherConfig {
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub signing: SigningConfig,
+    /// Serialisation format of stream message payloads
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    /// Compression applied to stream message payloads
+    #[serde(default)]
+    pub compression: ContentEncoding,
+}
+
+impl PublisherConfig {
+    /// Load the publisher configuration, falling back to the defaults if it is missing
+    pub fn load() -> Self {
+        match std::fs::read(PUBLISHER_CONFIG_PATH) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap(),
+            Err(_) => PublisherConfig::default(),
+        }
+    }
+}
+
+/// MQTT ingest bridge options
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Topic filters to subscribe to, e.g. `sensors/+/reading`
+    pub topics: Vec<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "alvarium_demo_pub".to_string(),
+            topics: vec!["sensors/#".to_string()],
+        }
+    }
+}
+
+/// Batch publishing options, readings of a sensor are grouped into a single stream message once
+/// `maxReadings` are queued or the oldest one has waited `maxWaitSecs`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchConfig {
+    pub enabled: bool,
+    pub max_readings: usize,
+    pub max_wait_secs: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            enabled: false,
+            max_readings: 10,
+            max_wait_secs: 60,
+        }
+    }
+}
+
+/// Heartbeat options, a heartbeat is sent on the base branch every `intervalSecs`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            enabled: true,
+            interval_secs: 60,
+        }
+    }
+}
+
+/// Per sensor signing options
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SigningConfig {
+    /// Directory holding the key pair of each simulated sensor
+    pub keystore: String,
+    #[serde(default)]
+    pub faults: FaultConfig,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            keystore: "config/keys/sensors".to_string(),
+            faults: FaultConfig::default(),
+        }
+    }
+}
+
+/// Invalid signature injection, a `rate` share of the readings of the listed sensors are given an
+/// invalid signature of the configured `mode`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FaultConfig {
+    pub sensors: Vec<String>,
+    pub rate: f64,
+    pub mode: FaultMode,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            sensors: vec!["Flow_Sensor_2".to_string()],
+            rate: 1.0,
+            mode: FaultMode::Zeroed,
+        }
+    }
+}
+
+/// Kind of invalid signature produced by fault injection
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FaultMode {
+    /// An all zero signature
+    Zeroed,
+    /// A valid signature from a key that does not belong to the sensor
+    ForeignKey,
+    /// A signature from the sensor key over different bytes than the published ones
+    Tampered,
+}
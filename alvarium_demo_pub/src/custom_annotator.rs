@@ -1,17 +1,22 @@
 use std::ops::Range;
 use std::string::ToString;
+use std::sync::{Arc, RwLock};
 use alvarium_annotator::{Annotation, Annotator, constants, derive_hash, serialise_and_sign};
 use alvarium_annotator::constants::AnnotationType;
 use alvarium_sdk_rust::{config, factories::new_hash_provider, providers::sign_provider::SignatureProviderWrap};
 use alvarium_sdk_rust::config::Signable;
 use alvarium_sdk_rust::factories::new_signature_provider;
+use crypto::signatures::ed25519::{PublicKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
 use crate::mock_sensor::SensorReading;
 use crate::errors::Result;
+use crate::sensor_keys::SensorKeys;
 
 
 lazy_static! {
     /// Annotation Type definition
     pub static ref ANNOTATION_THRESHOLD: AnnotationType = AnnotationType("threshold".to_string());
+    /// Same type as the sdk pki annotator, so that subscribers score both alike
+    pub static ref ANNOTATION_SENSOR_PKI: AnnotationType = AnnotationType("pki".to_string());
 }
 
 /// Defines a new annotator type that will implement the Annotator trait
@@ -78,3 +83,75 @@ impl Annotator for ThresholdAnnotator {
         }
     }
 }
+
+/// PKI annotator verifying each reading against the public key of the sensor it originates from,
+/// rather than against the single key of the sdk configuration
+pub struct SensorPkiAnnotator {
+    /// Hashing algorithm used for checksums
+    hash: constants::HashType,
+    /// Type of annotation (a wrapper around a string definition)
+    kind: AnnotationType,
+    /// Signature provider for signing data
+    sign: SignatureProviderWrap,
+    /// Sensor id -> public key registry
+    keys: Arc<RwLock<SensorKeys>>,
+}
+
+impl SensorPkiAnnotator {
+    pub fn new(cfg: &config::SdkInfo, keys: Arc<RwLock<SensorKeys>>) -> Result<impl Annotator<Error = alvarium_sdk_rust::errors::Error>> {
+        Ok(SensorPkiAnnotator {
+            hash: cfg.hash.hash_type.clone(),
+            kind: ANNOTATION_SENSOR_PKI.clone(),
+            sign: new_signature_provider(&cfg.signature)?,
+            keys,
+        })
+    }
+
+    /// Whether the signature of the signable was produced by the key registered for its sensor,
+    /// readings of sensors without a registered key are not trusted
+    fn verify(&self, signable: &Signable) -> bool {
+        let reading: SensorReading = match serde_json::from_str(&signable.seed) {
+            Ok(reading) => reading,
+            Err(_) => return false,
+        };
+        let public_key = match self.keys.read().unwrap().get(&reading.id) {
+            Some(key) => key.clone(),
+            None => return false,
+        };
+
+        let public_key = hex::decode(public_key.trim()).ok()
+            .and_then(|bytes| <[u8; PUBLIC_KEY_LENGTH]>::try_from(bytes).ok())
+            .and_then(|bytes| PublicKey::try_from_bytes(bytes).ok());
+        let signature = hex::decode(&signable.signature).ok()
+            .and_then(|bytes| <[u8; SIGNATURE_LENGTH]>::try_from(bytes).ok())
+            .map(Signature::from_bytes);
+        match (public_key, signature) {
+            (Some(public_key), Some(signature)) => public_key.verify(&signature, signable.seed.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+impl Annotator for SensorPkiAnnotator {
+    type Error = alvarium_sdk_rust::errors::Error;
+    fn annotate(&mut self, data: &[u8]) -> alvarium_sdk_rust::errors::Result<Annotation> {
+        let hasher = new_hash_provider(&self.hash)?;
+        let signable: std::result::Result<Signable, serde_json::Error> = serde_json::from_slice(data);
+        let (key, verified) = match signable {
+            Ok(signable) => (derive_hash(hasher, signable.seed.as_bytes()), self.verify(&signable)),
+            // Unsigned data cannot satisfy a signature check
+            Err(_) => (derive_hash(hasher, data), false),
+        };
+        match gethostname::gethostname().to_str() {
+            Some(host) => {
+                let mut annotation = Annotation::new(&key, self.hash.clone(), host, self.kind.clone(), verified);
+                let signature = serialise_and_sign(&self.sign, &annotation)?;
+                annotation.with_signature(&signature);
+                Ok(annotation)
+            },
+            None => {
+                Err(alvarium_sdk_rust::errors::Error::NoHostName.into())
+            }
+        }
+    }
+}
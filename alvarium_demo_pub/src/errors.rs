@@ -11,6 +11,20 @@ pub enum Error {
 
     #[error("Alvarium error: {0}")]
     AlvariumSdkError(alvarium_sdk_rust::errors::Error),
+
+    #[error("Compression error: {0}")]
+    CompressionError(std::io::Error),
+    #[error("Serialisation error: {0}")]
+    SerialisationError(String),
+
+    #[error("Stream error: {0}")]
+    StreamError(String),
+
+    #[error("Keystore error for {0}: {1}")]
+    KeystoreError(String, String),
+
+    #[error("MQTT client error: {0}")]
+    MqttClientError(rumqttc::ClientError),
 }
 
 impl From<alvarium_annotator::Error> for Error {
@@ -25,6 +39,12 @@ impl From<alvarium_sdk_rust::errors::Error> for Error {
     }
 }
 
+impl From<rumqttc::ClientError> for Error {
+    fn from(e: rumqttc::ClientError) -> Self {
+        Error::MqttClientError(e)
+    }
+}
+
 impl From<Error> for alvarium_sdk_rust::errors::Error {
     fn from(e: Error) -> Self {
         alvarium_sdk_rust::errors::Error::External(Box::new(e))
@@ -2,25 +2,65 @@ use std::net::SocketAddr;
 use std::sync::{Arc};
 use hyper::{Body, header, Request, Response, Server, StatusCode};
 use hyper::service::{make_service_fn, service_fn};
+use hyper::Method;
+use alvarium_sdk_rust::config::Signable;
 use streams::{Address, User};
-use streams::id::{Permissioned, Psk};
+use streams::id::{Identifier, Permissioned};
 use streams::transport::utangle::Client;
-use crate::BASE_TOPIC;
+use crate::metrics::METRICS;
+use crate::mock_sensor::{SensorDescription, SensorReading};
+use crate::pipeline::{validate_reading, IngestedReading};
+use crate::subscriptions::{generate_psk, identifier_from_hex, Subscriptions};
+use crate::topics::{TopicInfo, Topics};
 use std::str::FromStr;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
 
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Shared state handed to every request handler
+#[derive(Clone)]
+pub struct AppState {
+    pub user: Arc<Mutex<User<Client>>>,
+    pub subscriptions: Arc<Mutex<Subscriptions>>,
+    /// Topics that already exist on the stream and can be subscribed to
+    pub topics: Arc<Mutex<Topics>>,
+    /// Topic the annotations are written to
+    pub annotation_topic: String,
+    /// Annotators configured in the sdk
+    pub annotators: Vec<String>,
+    /// Data sources published on the stream
+    pub sensors: Arc<Mutex<Vec<SensorDescription>>>,
+    /// Hex encoded public key of the publisher signature provider
+    pub public_key: String,
+    /// Host and port of the tangle node, used to check it can be reached
+    pub node_address: String,
+    /// Hands readings pushed by external devices over to the publishing loop
+    pub ingest: mpsc::Sender<IngestedReading>,
+}
+
+impl AppState {
+    /// Subscribers only ever read, except the sdk stream user which writes to the annotation topic
+    fn permission(&self, subscriptions: &Subscriptions, topic: &str, identifier: Identifier) -> Permissioned<Identifier> {
+        if topic.eq(&self.annotation_topic) && subscriptions.is_writer(&identifier.to_string()) {
+            Permissioned::Admin(identifier)
+        } else {
+            Permissioned::Read(identifier)
+        }
+    }
+}
+
 /// Starts an http server for receiving subscription requests
-pub async fn start(user: Arc<Mutex<User<Client>>>) -> Result<(), GenericError> {
+pub async fn start(state: AppState) -> Result<(), GenericError> {
     let addr = SocketAddr::from(([0, 0, 0, 0], 8900));
 
     let service = make_service_fn(move |_| {
-        let user = user.clone();
+        let state = state.clone();
         async {
             Ok::<_, GenericError>(service_fn(move |req| {
-                handle_request(req, user.clone())
+                handle_request(req, state.clone())
             }))
         }
     });
@@ -31,13 +71,23 @@ pub async fn start(user: Arc<Mutex<User<Client>>>) -> Result<(), GenericError> {
     Ok(())
 }
 
-// Handler to manage the get_announcement_id() and subscribe() api calls
-async fn handle_request(req: Request<Body>, user: Arc<Mutex<User<Client>>>) -> Result<Response<Body>, GenericError> {
+// Handler to manage the get_announcement_id(), subscribe() and revocation api calls
+async fn handle_request(req: Request<Body>, state: AppState) -> Result<Response<Body>, GenericError> {
+    // Removes a subscriber from every topic it was granted access to
+    if req.method() == Method::DELETE {
+        if let Some(identifier) = req.uri().path().strip_prefix("/subscriptions/") {
+            let rotate_psk = req.uri().query()
+                .map(|q| q.split('&').any(|param| param.eq("rotate_psk=true")))
+                .unwrap_or(false);
+            return revoke_response(identifier.to_string(), rotate_psk, state).await
+        }
+    }
+
     match req.uri().path() {
         // Returns the announcement id of the stream created by the publisher instance
         "/get_announcement_id" => {
             // Generate a new announcement ID and send it through the channel.
-            let announcement_id = user.lock().await.stream_address().unwrap();
+            let announcement_id = state.user.lock().await.stream_address().unwrap();
 
             #[derive(serde::Serialize, serde::Deserialize)]
             struct AnnouncementResponse {
@@ -52,8 +102,31 @@ async fn handle_request(req: Request<Body>, user: Arc<Mutex<User<Client>>>) -> R
             let response = Response::new(Body::from(announcement));
             Ok(response)
         },
+        // Liveness probe, answering at all means the process is up
+        "/healthz" => {
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::from("ok"))
+                .unwrap();
+            Ok(response)
+        },
+        // Readiness probe, checks the stream, the node and the last send
+        "/readyz" => ready_response(state).await,
+        // Prometheus metrics
+        "/metrics" => {
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(METRICS.render()))
+                .unwrap();
+            Ok(response)
+        },
+        // Returns the stream metadata so subscribers can discover topics and sensors
+        "/stream" => stream_response(state).await,
+        // Accepts readings from external devices
+        "/readings" if req.method() == Method::POST => ingest_response(req, state).await,
         // Adds subscriber to publisher
-        "/subscribe" => subscribe_response(req, user).await,
+        "/subscribe" => subscribe_response(req, state).await,
         _ => {
             // Respond with a 404 Not Found for other paths.
             let response = Response::builder()
@@ -66,36 +139,72 @@ async fn handle_request(req: Request<Body>, user: Arc<Mutex<User<Client>>>) -> R
 }
 
 // Attempts to unpack a subscription request, if successful the subscription message will be
-// retrieved from the distributed network, and once processed, a new branch will be created for the
+// retrieved from the distributed network, and once processed, a keyload is sent on each of the
+// requested topics. Requests for topics that do not exist on the stream are rejected.
 pub async fn subscribe_response(
     req: Request<Body>,
-    user: Arc<Mutex<User<Client>>>,
+    state: AppState,
 ) -> Result<Response<Body>, GenericError> {
+    METRICS.subscription_requested();
     let data = hyper::body::to_bytes(req.into_body()).await?;
 
     let response;
     let json_data: serde_json::Result<SubscriptionRequest> = serde_json::from_slice(&data);
     match json_data {
         Ok(sub_req) => {
-            let mut user = user.lock().await;
+            let topics = sub_req.topics();
+            let available = state.topics.lock().await.names();
+            let unknown = topics.iter()
+                .filter(|topic| !available.contains(topic))
+                .cloned()
+                .collect::<Vec<String>>();
+            if topics.is_empty() || !unknown.is_empty() {
+                log::warn!("Rejected subscription request for unknown topics: {:?}", unknown);
+                let response = Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(format!("Unknown topics requested: {:?}, available topics: {:?}", unknown, available)))
+                    .unwrap();
+                return Ok(response)
+            }
+
+            let mut user = state.user.lock().await;
+            let mut subscriptions = state.subscriptions.lock().await;
             let sub_address = Address::from_str(&sub_req.address).unwrap();
             let msg = user.receive_message(sub_address).await.unwrap();
             let sub = msg.as_subscription().unwrap();
-            let _ = user.new_branch(BASE_TOPIC, sub_req.topic.as_str()).await;
-            let psk = Psk::from_seed("A pre shared key seed").to_pskid();
-            let keyload = user.send_keyload(
-                sub_req.topic.as_str(),
-                vec![Permissioned::Admin(sub.subscriber_identifier.clone())],
-                vec![psk]
-            )
-                .await
-                .unwrap();
+
+            let subscriber = sub.subscriber_identifier.to_string();
+
+            let psk = subscriptions.psk_id();
+            let mut keyloads = Vec::new();
+            for topic in &topics {
+                // Register the subscriber so the keyload can be re-issued without it on revocation
+                subscriptions.add(topic, &subscriber);
+
+                let keyload = user.send_keyload(
+                    topic.as_str(),
+                    subscriptions.subscribers(topic)
+                        .into_iter()
+                        .map(|identifier| state.permission(&subscriptions, topic, identifier))
+                        .collect(),
+                    vec![psk]
+                )
+                    .await
+                    .unwrap();
+                log::info!("Keyload for {} sent: {}", topic, keyload.address());
+                state.topics.lock().await.sent(topic, keyload.address().to_string());
+                keyloads.push(keyload.address().to_string());
+            }
+            subscriptions.save();
+            state.topics.lock().await.save();
 
             response = Response::builder()
                 .status(StatusCode::OK)
                 .header(header::CONTENT_TYPE, "application/json")
                 .header("Access-Control-Allow-Origin", "*")
-                .body(Body::from("Subscription processed, keyload link: ".to_owned() + &keyload.address().to_string()))
+                .body(Body::from("Subscription processed, keyload links: ".to_owned() + &keyloads.join(", ")))
                 .unwrap();
         },
         Err(e) => {
@@ -112,13 +221,255 @@ pub async fn subscribe_response(
     Ok(response)
 }
 
+// Accepts a reading pushed by an external device, either as a plain `SensorReading` or as a
+// `Signable` carrying the serialised reading and the device signature. Valid readings are queued to
+// be annotated and published on the sensor branch, which is created on first sight.
+pub async fn ingest_response(req: Request<Body>, state: AppState) -> Result<Response<Body>, GenericError> {
+    let data = hyper::body::to_bytes(req.into_body()).await?;
+
+    let ingested = match serde_json::from_slice::<SensorReading>(&data) {
+        Ok(reading) => Ok(IngestedReading { reading, signed: None }),
+        Err(_) => serde_json::from_slice::<Signable>(&data)
+            .map_err(|_| "Malformed json request".to_string())
+            .and_then(|signable| {
+                serde_json::from_str::<SensorReading>(&signable.seed)
+                    .map(|reading| IngestedReading { reading, signed: Some(signable) })
+                    .map_err(|_| "Signed seed is not a sensor reading".to_string())
+            }),
+    }
+        .and_then(|ingested| validate_reading(&ingested.reading).map(|_| ingested));
+
+    let response = match ingested {
+        Ok(ingested) => {
+            let id = ingested.reading.id.clone();
+            match state.ingest.send(ingested).await {
+                Ok(_) => Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(format!("Reading from {} queued for publishing", id)))
+                    .unwrap(),
+                Err(_) => Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from("Publishing loop is not running"))
+                    .unwrap(),
+            }
+        },
+        Err(e) => {
+            log::warn!("Rejected reading: {}", e);
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from(e))
+                .unwrap()
+        }
+    };
+
+    Ok(response)
+}
 
-// Subscription Request as
+// The publisher is ready once the stream is restored, the tangle node accepts connections and the
+// last message sent to the stream went through
+pub async fn ready_response(state: AppState) -> Result<Response<Body>, GenericError> {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct ReadyResponse {
+        stream_restored: bool,
+        node_reachable: bool,
+        last_send_ok: bool,
+    }
+
+    let stream_restored = state.user.lock().await.stream_address().is_some();
+    let node_reachable = matches!(
+        tokio::time::timeout(Duration::from_secs(2), TcpStream::connect(&state.node_address)).await,
+        Ok(Ok(_))
+    );
+    let last_send_ok = METRICS.last_send_ok();
+
+    let status = if stream_restored && node_reachable && last_send_ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&ReadyResponse {
+            stream_restored,
+            node_reachable,
+            last_send_ok,
+        }).unwrap()))
+        .unwrap();
+    Ok(response)
+}
+
+// Describes the stream: its announcement, author, branches, annotators and data sources
+pub async fn stream_response(state: AppState) -> Result<Response<Body>, GenericError> {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct StreamResponse {
+        announcement_id: String,
+        author: String,
+        public_key: String,
+        topics: Vec<TopicInfo>,
+        annotation_topic: String,
+        annotators: Vec<String>,
+        sensors: Vec<SensorDescription>,
+    }
+
+    let (announcement_id, author) = {
+        let user = state.user.lock().await;
+        (
+            user.stream_address().map(|a| a.to_string()).unwrap_or_default(),
+            user.identifier().map(|id| id.to_string()).unwrap_or_default(),
+        )
+    };
+
+    let stream = serde_json::to_vec(&StreamResponse {
+        announcement_id,
+        author,
+        public_key: state.public_key.clone(),
+        topics: state.topics.lock().await.list().to_vec(),
+        annotation_topic: state.annotation_topic.clone(),
+        annotators: state.annotators.clone(),
+        sensors: state.sensors.lock().await.clone(),
+    }).unwrap();
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(stream))
+        .unwrap();
+    Ok(response)
+}
+
+// Re-issues keyloads on every topic the subscriber had access to, leaving it out of the new
+// permission set so that messages sent after the keyload can no longer be read by it. If the psk
+// is rotated as well, every topic is re-keyed since the previous psk holders are all cut off. The
+// registry is only updated once every keyload went through, so a failed revocation can be retried.
+pub async fn revoke_response(
+    identifier: String,
+    rotate_psk: bool,
+    state: AppState,
+) -> Result<Response<Body>, GenericError> {
+    let mut user = state.user.lock().await;
+    let mut subscriptions = state.subscriptions.lock().await;
+
+    let mut topics = subscriptions.topics_of(&identifier);
+    if topics.is_empty() && !rotate_psk {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from("No subscription found for identifier"))
+            .unwrap();
+        return Ok(response)
+    }
+
+    let old_psk = subscriptions.psk_id();
+    let mut psk = old_psk;
+    if !rotate_psk {
+        log::warn!("Revoking {} without rotating the pre shared key, it can still read the topics if it holds the key", identifier);
+    }
+    if rotate_psk {
+        let new = generate_psk();
+        psk = new.to_pskid();
+        user.add_psk(psk, new);
+        topics = subscriptions.topics();
+    }
+
+    let mut keyloads = Vec::new();
+    for topic in &topics {
+        let sent = user.send_keyload(
+            topic.as_str(),
+            subscriptions.subscribers_except(topic, &identifier)
+                .into_iter()
+                .map(|identifier| state.permission(&subscriptions, topic, identifier))
+                .collect(),
+            vec![psk]
+        )
+            .await;
+        let keyload = match sent {
+            Ok(keyload) => keyload,
+            Err(e) => {
+                // Nothing is recorded, the keyloads already sent are superseded when the request is retried
+                log::error!("Failed to re-issue keyload for {} without {}: {}", topic, identifier, e);
+                if rotate_psk {
+                    user.remove_psk(psk);
+                }
+                state.topics.lock().await.save();
+                let response = Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(format!("Failed to re-issue keyload for {}, the subscription was kept", topic)))
+                    .unwrap();
+                return Ok(response)
+            }
+        };
+        log::info!("Re-issued keyload for {} without {}: {}", topic, identifier, keyload.address());
+        state.topics.lock().await.sent(topic, keyload.address().to_string());
+        keyloads.push(keyload.address().to_string());
+    }
+    state.topics.lock().await.save();
+
+    subscriptions.remove(&identifier);
+    if let Some(revoked) = identifier_from_hex(&identifier) {
+        user.remove_subscriber(&revoked);
+    }
+    if rotate_psk {
+        subscriptions.set_psk_id(psk);
+        user.remove_psk(old_psk);
+    }
+    subscriptions.save();
+    drop(subscriptions);
+    drop(user);
+    // The backup is encrypted and holds the rotated psk, which is not kept anywhere else
+    crate::backup(state.user.clone()).await;
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct RevocationResponse {
+        revoked: String,
+        psk_rotated: bool,
+        keyloads: Vec<String>,
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(serde_json::to_vec(&RevocationResponse {
+            revoked: identifier,
+            psk_rotated: rotate_psk,
+            keyloads,
+        }).unwrap()))
+        .unwrap();
+    Ok(response)
+}
+
+
+// Subscription Request as sent by a subscriber. A single `topic` is still accepted for clients
+// that only ever ask for one, `topics` allows subscribing to several existing topics at once
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct SubscriptionRequest {
     address: String,
     identifier: String,
     #[serde(rename="idType")]
     id_type: u8,
-    topic: String,
+    #[serde(default)]
+    topic: Option<String>,
+    #[serde(default)]
+    topics: Vec<String>,
+}
+
+impl SubscriptionRequest {
+    fn topics(&self) -> Vec<String> {
+        let mut topics = self.topics.clone();
+        if let Some(topic) = &self.topic {
+            if !topics.contains(topic) {
+                topics.push(topic.clone());
+            }
+        }
+        topics
+    }
 }
\ No newline at end of file
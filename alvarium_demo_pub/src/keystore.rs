@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::PathBuf;
+use crypto::signatures::ed25519::{SecretKey, SECRET_KEY_LENGTH, SIGNATURE_LENGTH};
+use rand::Rng;
+use crate::config::{FaultConfig, FaultMode};
+use crate::errors::{Error, Result};
+
+/// Signing identities of the sensors simulated by the publisher, each one holding its own ed25519
+/// key pair stored as `<sensor id>.key` (hex private key) and `<sensor id>.pub` (hex public key)
+pub struct Keystore {
+    path: PathBuf,
+}
+
+impl Keystore {
+    /// Open the keystore directory, creating it if it does not exist yet
+    pub fn open(path: &str) -> Result<Self> {
+        fs::create_dir_all(path).map_err(|e| Error::KeystoreError(path.to_string(), e.to_string()))?;
+        Ok(Keystore { path: PathBuf::from(path) })
+    }
+
+    /// Key pair of a sensor, read from the keystore or generated and saved to it the first time the
+    /// sensor is seen
+    fn key(&self, sensor: &str) -> Result<SecretKey> {
+        let key_path = self.path.join(format!("{}.key", sensor));
+        let key = match fs::read_to_string(&key_path) {
+            Ok(encoded) => {
+                let bytes: [u8; SECRET_KEY_LENGTH] = hex::decode(encoded.trim()).ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| Error::KeystoreError(key_path.display().to_string(), "malformed key".to_string()))?;
+                SecretKey::from_bytes(&bytes)
+            },
+            Err(_) => {
+                log::info!("Generating signing key for {}", sensor);
+                let key = SecretKey::generate()
+                    .map_err(|e| Error::KeystoreError(sensor.to_string(), e.to_string()))?;
+                fs::write(&key_path, hex::encode(key.as_slice()))
+                    .map_err(|e| Error::KeystoreError(key_path.display().to_string(), e.to_string()))?;
+                key
+            },
+        };
+
+        // The public key is kept next to the private one for verifiers to pick up
+        let public_path = self.path.join(format!("{}.pub", sensor));
+        fs::write(&public_path, hex::encode(key.public_key().as_slice()))
+            .map_err(|e| Error::KeystoreError(public_path.display().to_string(), e.to_string()))?;
+        Ok(key)
+    }
+
+    /// Signer for a sensor, injecting invalid signatures if the sensor is listed in the fault config
+    pub fn signer(&self, sensor: &str, faults: &FaultConfig) -> Result<SensorSigner> {
+        let fault = faults.sensors.iter().any(|s| s.eq(sensor))
+            .then(|| (faults.mode, faults.rate.clamp(0.0, 1.0)));
+        Ok(SensorSigner { key: self.key(sensor)?, fault })
+    }
+}
+
+/// Signs the readings of one sensor with its own key
+pub struct SensorSigner {
+    key: SecretKey,
+    /// Kind of invalid signature to produce and the share of readings it is produced for
+    fault: Option<(FaultMode, f64)>,
+}
+
+impl SensorSigner {
+    /// Hex encoded public key of the sensor
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.public_key().as_slice())
+    }
+
+    /// Hex encoded signature of the data, or an invalid one when a fault is injected
+    pub fn sign(&self, data: &[u8]) -> String {
+        let fault = self.fault.filter(|(_, rate)| rand::thread_rng().gen_bool(*rate));
+        match fault.map(|(mode, _)| mode) {
+            None => hex::encode(self.key.sign(data).to_bytes()),
+            Some(FaultMode::Zeroed) => hex::encode([0u8; SIGNATURE_LENGTH]),
+            // A well formed signature from a key the verifiers do not know about
+            Some(FaultMode::ForeignKey) => match SecretKey::generate() {
+                Ok(foreign) => hex::encode(foreign.sign(data).to_bytes()),
+                Err(_) => hex::encode([0u8; SIGNATURE_LENGTH]),
+            },
+            // A signature from the right key over different bytes
+            Some(FaultMode::Tampered) => {
+                let mut tampered = data.to_vec();
+                tampered.push(0);
+                hex::encode(self.key.sign(&tampered).to_bytes())
+            },
+        }
+    }
+}
@@ -1,27 +1,45 @@
+mod annotation_publisher;
+mod codec;
+mod config;
 mod custom_annotator;
 mod mock_sensor;
 mod http;
 mod errors;
+mod keystore;
 mod logger;
+mod metrics;
+mod mqtt;
+mod pipeline;
+mod sensor_keys;
+mod subscriptions;
+mod topics;
 
 use std::fs;
-use std::sync::{Arc};
-use tokio::sync::Mutex;
-use std::time::Duration;
-use alvarium_annotator::{Annotator, SignProvider};
-use alvarium_sdk_rust::config::{SdkInfo, Signable, StreamConfig};
-use alvarium_sdk_rust::factories::{new_annotator, new_signature_provider};
-use alvarium_sdk_rust::providers::stream_provider::DemiaPublisher;
+use std::sync::{Arc, RwLock};
+use tokio::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+use alvarium_annotator::Annotator;
+use alvarium_sdk_rust::config::{SdkInfo, StreamConfig};
+use alvarium_sdk_rust::factories::new_annotator;
 use alvarium_sdk_rust::sdk::SDK;
 use crypto::signatures::ed25519::SecretKey;
 use streams::id::{Ed25519, Psk};
 use streams::transport::utangle::Client;
 use streams::User;
-use crate::custom_annotator::ThresholdAnnotator;
+use crate::annotation_publisher::AnnotationPublisher;
+use crate::config::PublisherConfig;
+use crate::custom_annotator::{SensorPkiAnnotator, ThresholdAnnotator};
+use crate::keystore::Keystore;
+use crate::metrics::METRICS;
 use crate::mock_sensor::Sensor;
+use crate::pipeline::{Pipeline, SignedReading};
+use crate::sensor_keys::SensorKeys;
+use crate::subscriptions::Subscriptions;
+use crate::topics::Topics;
 
 pub const BASE_TOPIC: &'static str = "Base Topic";
 pub const SENSOR_TOPIC: &'static str = "Sensor Topic";
+pub const PSK_SEED: &'static str = "A pre shared key seed";
 
 #[tokio::main]
 async fn main() {
@@ -30,14 +48,93 @@ async fn main() {
 
     // Get configurations from the static configuration bytes
     let sdk_info: SdkInfo = serde_json::from_slice(CONFIG_BYTES.as_slice()).unwrap();
+    let publisher_config = PublisherConfig::load();
 
     // Create a new stream instance, or retrieve an existing one
     let (user, retrieved) = create_stream(&sdk_info).await;
     let stream_author = Arc::new(Mutex::new(user));
+
+    // Create 2 mock sensors
+    let sensor1 = Sensor("Flow_Sensor_1".to_string());
+    let sensor2 = Sensor("Flow_Sensor_2".to_string());
+    let annotation_topic = annotation_topic(&sdk_info);
+
+    // Restore the registry of stream branches
+    let topics = Arc::new(Mutex::new(Topics::load()));
+
+    // If the user instance is new, make sure to create a new branch for each data source and one
+    // for the annotations, so that subscribers can be granted access to existing topics
+    if !retrieved {
+        let mut topics = topics.lock().await;
+        *topics = Topics::default();
+        for topic in [sensor1.0.clone(), sensor2.0.clone(), annotation_topic.clone()] {
+            let branch = stream_author.lock().await.new_branch(BASE_TOPIC, topic.clone()).await.unwrap();
+            topics.register(&topic, Some(chrono::Utc::now()));
+            topics.sent(&topic, branch.address().to_string());
+        }
+        topics.save();
+        drop(topics);
+        backup(stream_author.clone()).await;
+    } else {
+        // Branches created before the registry existed are registered without a creation time
+        let mut topics = topics.lock().await;
+        for topic in [&sensor1.0, &sensor2.0, &annotation_topic] {
+            topics.register(topic, None);
+        }
+    }
+
+    // Restore the registry of subscribers that have been granted access to the stream topics, and enrol
+    // the sdk stream user, read from its backup, as the writer of the annotation topic
+    let annotation_writer = annotation_publisher::writer_identifier(&sdk_info.stream).await.unwrap();
+    let mut subscriptions = Subscriptions::load();
+    if subscriptions.add_writer(&annotation_writer) {
+        log::info!("Enrolled {} as the annotation writer", annotation_writer);
+        subscriptions.save();
+    }
+    let subscriptions = Arc::new(Mutex::new(subscriptions));
+    let sensors = Arc::new(Mutex::new(vec![
+        sensor1.describe("Mock flow sensor producing well behaved readings with valid signatures"),
+        sensor2.describe("Mock flow sensor producing noisy readings"),
+    ]));
+    // Readings pushed by external devices through the api are handed over to the main loop
+    let (ingest_sender, mut ingest_receiver) = mpsc::channel(100);
     // Start the api server
-    tokio::spawn(http::start(stream_author.clone()));
-    // Prepare the signature provider
-    let signature_provider = new_signature_provider( &sdk_info.signature).unwrap();
+    tokio::spawn(http::start(http::AppState {
+        user: stream_author.clone(),
+        subscriptions,
+        topics: topics.clone(),
+        annotation_topic: annotation_topic.clone(),
+        annotators: sdk_info.annotators.iter().map(|ann| ann.0.clone()).collect(),
+        sensors: sensors.clone(),
+        public_key: fs::read_to_string(&sdk_info.signature.public_key_info.path).unwrap_or_default(),
+        node_address: node_address(&sdk_info),
+        ingest: ingest_sender.clone(),
+    }));
+    // Optionally bridge readings from an MQTT broker into the same pipeline
+    if publisher_config.mqtt.enabled {
+        let mqtt_config = publisher_config.mqtt.clone();
+        let mqtt_ingest = ingest_sender.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mqtt::start(mqtt_config, mqtt_ingest).await {
+                log::error!("MQTT bridge stopped: {}", e);
+            }
+        });
+    }
+    // Each mock sensor signs its readings with its own key, invalid signatures are only produced
+    // through the configured fault injection
+    let keystore = Keystore::open(&publisher_config.signing.keystore).unwrap();
+    let signer1 = keystore.signer(&sensor1.0, &publisher_config.signing.faults).unwrap();
+    let signer2 = keystore.signer(&sensor2.0, &publisher_config.signing.faults).unwrap();
+
+    // Register the keys of the mock sensors alongside the keys of real devices
+    let sensor_keys = Arc::new(RwLock::new(SensorKeys::load()));
+    {
+        let mut sensor_keys = sensor_keys.write().unwrap();
+        let registered = sensor_keys.register(&sensor1.0, signer1.public_key());
+        if sensor_keys.register(&sensor2.0, signer2.public_key()) || registered {
+            sensor_keys.save();
+        }
+    }
 
     // Create a vector of annotators for the alvarium sdk instance
     let mut annotators: Vec<Box<dyn Annotator<Error = alvarium_sdk_rust::errors::Error> + '_>> = Vec::new();
@@ -45,13 +142,18 @@ async fn main() {
         match ann.0.as_str() {
             // if the annotation type is the custom "threshold" then create a new custom ThresholdAnnotator
             "threshold" => annotators.push(Box::new(ThresholdAnnotator::new(&sdk_info, 180..200).unwrap())),
+            // readings are verified against the key of the sensor they come from
+            "pki" => annotators.push(Box::new(SensorPkiAnnotator::new(&sdk_info, sensor_keys.clone()).unwrap())),
             // else generate a new annotator from the sdk factory
             _ => annotators.push(new_annotator(ann.clone(), sdk_info.clone()).unwrap()),
         }
     }
 
-    // Create the alvarium SDK instance to annotate sensor data
-    let mut sdk: SDK<'_, DemiaPublisher> = SDK::new(sdk_info, annotators.as_mut_slice()).await
+    let annotator_kinds: Vec<String> = sdk_info.annotators.iter().map(|ann| ann.0.clone()).collect();
+
+    // Create the alvarium SDK instance to annotate sensor data. A new sdk stream user subscribes
+    // through the provider api, and is the only subscriber allowed to write to the annotation topic
+    let sdk: SDK<'_, AnnotationPublisher> = SDK::new(sdk_info.clone(), annotators.as_mut_slice()).await
         .map_err(|e| {
             // print out any error that might be occurring in SDK generation
             log::error!("Error: {}", e);
@@ -59,61 +161,69 @@ async fn main() {
         })
         .unwrap();
 
-    // Create 2 mock sensors
-    let sensor1 = Sensor("Flow_Sensor_1".to_string());
-    let sensor2 = Sensor("Flow_Sensor_2".to_string());
+    // Readings are sent by the stream author and annotated through the sdk, which publishes the
+    // annotations with its own stream user
+    let mut pipeline = Pipeline::new(
+        stream_author.clone(),
+        topics.clone(),
+        sensors,
+        sdk,
+        annotator_kinds,
+        annotation_topic,
+        sensor_keys.clone(),
+        sdk_info.hash.hash_type.clone(),
+        &publisher_config,
+    );
+    pipeline.publish_sensor_keys().await;
 
-    // If the user instance is new, make sure to create a new branch for each data source
-    if !retrieved {
-        stream_author.lock().await.new_branch(BASE_TOPIC, sensor1.0.clone()).await.unwrap();
-        stream_author.lock().await.new_branch(BASE_TOPIC, sensor2.0.clone()).await.unwrap();
-    }
-
-    // Main sensor loop
+    // Main sensor loop, producing mock readings every 10 seconds and publishing readings pushed by
+    // external devices as they arrive
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    // Checks for batches whose window has expired
+    let mut batch_interval = tokio::time::interval(Duration::from_secs(1));
+    // Periodic publisher liveness messages
+    let heartbeat_period = Duration::from_secs(publisher_config.heartbeat.interval_secs.max(1));
+    let mut heartbeat_interval = tokio::time::interval(heartbeat_period);
     loop {
-        // Generate readings to send
-        let val = sensor1.new_reading();
-        let val2 = sensor2.bad_reading();
-
-        let val_bytes = serde_json::to_vec(&val).unwrap();
-        let val2_bytes = serde_json::to_vec(&val2).unwrap();
-
-        log::info!("Sensor {} reading: {}", val.id, val.value);
-        log::info!("Sensor {} reading: {}", val2.id, val2.value);
-
-        // Send sensor data
-        stream_author.lock().await.message()
-            .with_topic(val.id.clone())
-            .with_payload(val_bytes.as_slice())
-            .signed()
-            .send()
-            .await
-            .unwrap();
-
-        stream_author.lock().await.message()
-            .with_topic(val2.id.clone())
-            .with_payload(val2_bytes.as_slice())
-            .signed()
-            .send()
-            .await
-            .unwrap();
-        backup(stream_author.clone()).await;
-
-        // Create a signable object for sensor 1 that provides a proper signature
-        let sig = signature_provider.sign(&serde_json::to_vec(&val).unwrap()).unwrap();
-        let data = Signable::new(serde_json::to_string(&val).unwrap(), sig);
+        tokio::select! {
+            _ = interval.tick() => {
+                // Generate readings to send
+                let val = sensor1.new_reading();
+                let val2 = sensor2.bad_reading();
 
-        // Create a signable object for sensor 2 that provides an improper signature
-        let sig = hex::encode([0u8; crypto::signatures::ed25519::SIGNATURE_LENGTH]);
-        let data2 = Signable::new(serde_json::to_string(&val2).unwrap(), sig);
+                // Each sensor signs its own readings
+                pipeline.publish(SignedReading::new(val, |bytes| signer1.sign(bytes))).await;
+                pipeline.publish(SignedReading::new(val2, |bytes| signer2.sign(bytes))).await;
+            },
+            Some(ingested) = ingest_receiver.recv() => {
+                // Readings that were not signed by the device stay unsigned, the gateway does not vouch
+                // for them
+                let signed = match ingested.signed {
+                    Some(signable) => SignedReading { reading: ingested.reading, signable },
+                    None => SignedReading::unsigned(ingested.reading),
+                };
+                pipeline.publish(signed).await;
+            },
+            _ = batch_interval.tick(), if publisher_config.batch.enabled => {
+                pipeline.flush_expired().await;
+            },
+            _ = heartbeat_interval.tick(), if publisher_config.heartbeat.enabled => {
+                let queued = ingest_sender.max_capacity() - ingest_sender.capacity();
+                pipeline.heartbeat(heartbeat_period, queued).await;
+            }
+        }
 
-        // Annotate the messages
-        sdk.create(data.to_bytes().as_slice()).await.unwrap();
-        sdk.create(data2.to_bytes().as_slice()).await.unwrap();
+        // Keys edited on disk are picked up and the new registry is announced on the stream
+        let reloaded = sensor_keys.write().unwrap().reload();
+        if reloaded {
+            pipeline.publish_sensor_keys().await;
+        }
 
-        backup(stream_author.clone()).await;
-        // Wait for 10 seconds and repeat
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        // Only a send changes the stream user, the ticks that sent nothing leave the backup as it is
+        if pipeline.take_sent() {
+            topics.lock().await.save();
+            backup(stream_author.clone()).await;
+        }
     }
 }
 
@@ -134,7 +244,7 @@ async fn create_stream(config: &SdkInfo) -> (User<Client>, bool) {
                 fs::write(&config.signature.private_key_info.path, hex::encode(sk.as_slice())).unwrap();
                 fs::write(&config.signature.public_key_info.path, hex::encode(sk.public_key().as_slice())).unwrap();
 
-                let psk = Psk::from_seed("A pre shared key seed");
+                let psk = Psk::from_seed(PSK_SEED);
                 let mut streams_author = User::builder()
                     .with_transport(client)
                     .with_identity(Ed25519::new(sk))
@@ -153,10 +263,30 @@ async fn create_stream(config: &SdkInfo) -> (User<Client>, bool) {
     }
 }
 
+/// Topic the sdk publishes annotations to, as set in the stream configuration
+fn annotation_topic(config: &SdkInfo) -> String {
+    if let StreamConfig::DemiaStreams(streams_config) = &config.stream.config {
+        streams_config.topic.clone()
+    } else {
+        panic!("Test configuration is not correct, should be DemiaStreams config")
+    }
+}
+
+/// Host and port of the tangle node from the stream configuration
+fn node_address(config: &SdkInfo) -> String {
+    if let StreamConfig::DemiaStreams(streams_config) = &config.stream.config {
+        format!("{}:{}", streams_config.tangle_node.host, streams_config.tangle_node.port)
+    } else {
+        panic!("Test configuration is not correct, should be DemiaStreams config")
+    }
+}
+
 async fn backup(author: Arc<Mutex<User<Client>>>) {
     // Backup the user instance
+    let start = Instant::now();
     let backup = author.lock().await.backup("unique password").await.unwrap();
     fs::write("user.bin", backup).unwrap();
+    METRICS.backup_completed(start.elapsed());
 }
 
 
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+lazy_static! {
+    /// Process wide metrics exposed through the `/metrics` endpoint
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Counters describing the publisher activity
+#[derive(Default)]
+pub struct Metrics {
+    readings_published: AtomicU64,
+    send_failures: AtomicU64,
+    annotation_failures: AtomicU64,
+    subscription_requests: AtomicU64,
+    backups: AtomicU64,
+    backup_micros: AtomicU64,
+    /// Whether the last message sent to the stream went through, starts false until a send succeeds
+    last_send_ok: AtomicBool,
+    /// Annotation type -> number of annotations created
+    annotations: Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn reading_published(&self) {
+        self.readings_published.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_succeeded(&self) {
+        self.last_send_ok.store(true, Ordering::Relaxed);
+    }
+
+    pub fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+        self.last_send_ok.store(false, Ordering::Relaxed);
+    }
+
+    /// The sdk failed to create or publish the annotations of a reading
+    pub fn annotation_failed(&self) {
+        self.annotation_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn subscription_requested(&self) {
+        self.subscription_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn backup_completed(&self, duration: Duration) {
+        self.backups.fetch_add(1, Ordering::Relaxed);
+        self.backup_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn annotation_created(&self, kind: &str) {
+        *self.annotations.lock().unwrap().entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn last_send_ok(&self) -> bool {
+        self.last_send_ok.load(Ordering::Relaxed)
+    }
+
+    /// Render the metrics in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("alvarium_readings_published_total", "Readings published to the stream", &self.readings_published),
+            ("alvarium_send_failures_total", "Messages that failed to be sent to the stream", &self.send_failures),
+            ("alvarium_annotation_failures_total", "Readings the sdk failed to annotate", &self.annotation_failures),
+            ("alvarium_subscription_requests_total", "Subscription requests received", &self.subscription_requests),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(out, "# HELP alvarium_annotations_created_total Annotations created per annotation type");
+        let _ = writeln!(out, "# TYPE alvarium_annotations_created_total counter");
+        let mut annotations = self.annotations.lock().unwrap().clone().into_iter().collect::<Vec<(String, u64)>>();
+        annotations.sort();
+        for (kind, count) in annotations {
+            let _ = writeln!(out, "alvarium_annotations_created_total{{type=\"{}\"}} {}", kind, count);
+        }
+
+        let _ = writeln!(out, "# HELP alvarium_backup_duration_seconds Time spent backing up the stream user");
+        let _ = writeln!(out, "# TYPE alvarium_backup_duration_seconds summary");
+        let _ = writeln!(
+            out,
+            "alvarium_backup_duration_seconds_sum {}",
+            self.backup_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "alvarium_backup_duration_seconds_count {}", self.backups.load(Ordering::Relaxed));
+        out
+    }
+}
@@ -4,6 +4,15 @@ use serde::{Deserialize, Serialize};
 pub struct Sensor(pub String);
 
 impl Sensor {
+    /// Describes the sensor for stream metadata consumers
+    pub fn describe(&self, description: &str) -> SensorDescription {
+        SensorDescription {
+            id: self.0.clone(),
+            topic: self.0.clone(),
+            description: description.to_string(),
+        }
+    }
+
     pub fn new_reading(&self) -> SensorReading {
         let mut rng = rand::thread_rng();
 
@@ -60,3 +69,12 @@ pub struct SensorReading {
     pub value: u8,
     pub timestamp: chrono::DateTime<chrono::Utc>
 }
+
+/// Description of a data source published in the stream metadata
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SensorDescription {
+    pub id: String,
+    /// Branch of the stream the sensor readings are published on
+    pub topic: String,
+    pub description: String,
+}
@@ -0,0 +1,243 @@
+use std::time::Duration;
+use alvarium_sdk_rust::config::Signable;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use crate::config::MqttConfig;
+use crate::errors::Result;
+use crate::mock_sensor::SensorReading;
+use crate::pipeline::{validate_reading, IngestedReading};
+
+/// Connects to the configured broker and forwards every message received on the subscribed topic
+/// filters to the publishing loop, the same way readings pushed through the http api are
+pub async fn start(config: MqttConfig, ingest: mpsc::Sender<IngestedReading>) -> Result<()> {
+    let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    log::info!("MQTT bridge connecting to {}:{} for {:?}", config.host, config.port, config.topics);
+
+    loop {
+        match eventloop.poll().await {
+            // A clean session starts without subscriptions, so they are made again on every (re)connection
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                for filter in &config.topics {
+                    client.subscribe(filter.as_str(), QoS::AtLeastOnce).await?;
+                }
+                log::info!("MQTT bridge connected, subscribed to {:?}", config.topics);
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                match map_message(&publish.topic, &publish.payload) {
+                    Ok(ingested) => {
+                        if ingest.send(ingested).await.is_err() {
+                            log::error!("Publishing loop is not running, stopping MQTT bridge");
+                            return Ok(())
+                        }
+                    },
+                    Err(e) => log::warn!("Rejected MQTT message on {}: {}", publish.topic, e),
+                }
+            },
+            Ok(_) => (),
+            Err(e) => {
+                // The event loop reconnects on the next poll, back off so a missing broker isn't hammered
+                log::error!("MQTT connection error: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Maps an MQTT message to a reading. The payload may be a full `SensorReading`, a `Signable` whose
+/// seed is a serialised `SensorReading`, a `{"value": .., "timestamp": ..}` object or a bare value.
+/// For the last two the sensor id is taken from the topic, `<prefix>/<sensor id>[/...]`, and a
+/// missing timestamp is set to the time of arrival.
+pub fn map_message(topic: &str, payload: &[u8]) -> std::result::Result<IngestedReading, String> {
+    #[derive(Deserialize)]
+    struct PartialReading {
+        value: u8,
+        timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    }
+
+    let ingested = if let Ok(reading) = serde_json::from_slice::<SensorReading>(payload) {
+        IngestedReading { reading, signed: None }
+    } else if let Ok(signable) = serde_json::from_slice::<Signable>(payload) {
+        let reading = serde_json::from_str::<SensorReading>(&signable.seed)
+            .map_err(|_| "Signed seed is not a sensor reading".to_string())?;
+        IngestedReading { reading, signed: Some(signable) }
+    } else {
+        let partial = match serde_json::from_slice::<PartialReading>(payload) {
+            Ok(partial) => partial,
+            Err(_) => {
+                let value = std::str::from_utf8(payload)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<u8>().ok())
+                    .ok_or_else(|| "Payload is not a known reading format".to_string())?;
+                PartialReading { value, timestamp: None }
+            }
+        };
+        IngestedReading {
+            reading: SensorReading {
+                id: sensor_id(topic),
+                value: partial.value,
+                timestamp: partial.timestamp.unwrap_or_else(chrono::Utc::now),
+            },
+            signed: None,
+        }
+    };
+
+    validate_reading(&ingested.reading)?;
+    Ok(ingested)
+}
+
+fn sensor_id(topic: &str) -> String {
+    let levels = topic.split('/').collect::<Vec<&str>>();
+    match levels.len() {
+        1 => levels[0].to_string(),
+        _ => levels[1].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::pipeline::SignedReading;
+    use super::*;
+
+    fn reading(id: &str, value: u8) -> SensorReading {
+        SensorReading { id: id.to_string(), value, timestamp: chrono::Utc::now() }
+    }
+
+    #[test]
+    fn sensor_id_is_the_second_topic_level() {
+        assert_eq!(sensor_id("Flow_Sensor_1"), "Flow_Sensor_1");
+        assert_eq!(sensor_id("sensors/Flow_Sensor_1"), "Flow_Sensor_1");
+        assert_eq!(sensor_id("sensors/Flow_Sensor_1/reading"), "Flow_Sensor_1");
+    }
+
+    #[test]
+    fn maps_full_reading() {
+        let payload = serde_json::to_vec(&reading("Flow_Sensor_1", 42)).unwrap();
+        let ingested = map_message("sensors/other/reading", &payload).unwrap();
+        assert_eq!(ingested.reading.id, "Flow_Sensor_1");
+        assert_eq!(ingested.reading.value, 42);
+        assert!(ingested.signed.is_none());
+    }
+
+    #[test]
+    fn maps_signed_reading() {
+        let signed = SignedReading::new(reading("Flow_Sensor_1", 42), |_| "abcd".to_string());
+        let payload = serde_json::to_vec(&signed.signable).unwrap();
+        let ingested = map_message("sensors/other/reading", &payload).unwrap();
+        assert_eq!(ingested.reading.id, "Flow_Sensor_1");
+        assert_eq!(ingested.reading.value, 42);
+        let signable = ingested.signed.unwrap();
+        assert_eq!(signable.seed, signed.signable.seed);
+        assert_eq!(signable.signature, "abcd");
+    }
+
+    #[test]
+    fn maps_partial_reading() {
+        let timestamp = chrono::Utc::now() - chrono::Duration::minutes(5);
+        let payload = serde_json::json!({ "value": 42, "timestamp": timestamp }).to_string();
+        let ingested = map_message("sensors/Flow_Sensor_1/reading", payload.as_bytes()).unwrap();
+        assert_eq!(ingested.reading.id, "Flow_Sensor_1");
+        assert_eq!(ingested.reading.value, 42);
+        assert_eq!(ingested.reading.timestamp, timestamp);
+        assert!(ingested.signed.is_none());
+
+        let before = chrono::Utc::now();
+        let ingested = map_message("sensors/Flow_Sensor_1", br#"{"value": 42}"#).unwrap();
+        assert!(ingested.reading.timestamp >= before);
+    }
+
+    #[test]
+    fn maps_bare_value() {
+        let before = chrono::Utc::now();
+        let ingested = map_message("sensors/Flow_Sensor_1", b" 42\n").unwrap();
+        assert_eq!(ingested.reading.id, "Flow_Sensor_1");
+        assert_eq!(ingested.reading.value, 42);
+        assert!(ingested.reading.timestamp >= before);
+        assert!(ingested.signed.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_payloads() {
+        assert!(map_message("sensors/Flow_Sensor_1", b"not a reading").is_err());
+        assert!(map_message("sensors/Flow_Sensor_1", b"4200").is_err());
+        let signable = serde_json::json!({ "seed": "not a reading", "signature": "abcd" }).to_string();
+        assert!(map_message("sensors/Flow_Sensor_1", signable.as_bytes()).is_err());
+        assert!(map_message("sensors/bad sensor id", b"42").is_err());
+    }
+
+    /// Reads one MQTT control packet, returning its first header byte and its variable part
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = stream.read_u8().await.unwrap();
+        let (mut length, mut shift) = (0usize, 0);
+        loop {
+            let byte = stream.read_u8().await.unwrap();
+            length += ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (header, body)
+    }
+
+    /// Stand-in broker side of a connection: accepts the connection, acknowledges the subscription
+    /// the bridge is expected to make, and returns the subscribed topic filter
+    async fn accept_subscription(stream: &mut TcpStream) -> String {
+        let (header, _) = read_packet(stream).await;
+        assert_eq!(header >> 4, 1, "expected CONNECT");
+        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+        let (header, body) = read_packet(stream).await;
+        assert_eq!(header >> 4, 8, "expected SUBSCRIBE");
+        let filter_length = u16::from_be_bytes([body[2], body[3]]) as usize;
+        let filter = String::from_utf8(body[4..4 + filter_length].to_vec()).unwrap();
+        stream.write_all(&[0x90, 0x03, body[0], body[1], 0x01]).await.unwrap();
+        filter
+    }
+
+    #[tokio::test]
+    async fn forwards_messages_and_subscribes_again_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttConfig {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            client_id: "alvarium_demo_pub_test".to_string(),
+            topics: vec!["sensors/#".to_string()],
+        };
+        let (sender, mut receiver) = mpsc::channel(10);
+        let bridge = tokio::spawn(start(config, sender));
+
+        // The first connection is dropped once subscribed, as a restarting broker would
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(accept_subscription(&mut stream).await, "sensors/#");
+        drop(stream);
+
+        // The subscriptions of a clean session are gone, the bridge has to make them again
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(accept_subscription(&mut stream).await, "sensors/#");
+
+        let topic = b"sensors/Flow_Sensor_1/reading";
+        let payload = b"42";
+        let mut publish = vec![0x30, (2 + topic.len() + payload.len()) as u8];
+        publish.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        publish.extend_from_slice(topic);
+        publish.extend_from_slice(payload);
+        stream.write_all(&publish).await.unwrap();
+
+        let ingested = tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ingested.reading.id, "Flow_Sensor_1");
+        assert_eq!(ingested.reading.value, 42);
+        bridge.abort();
+    }
+}
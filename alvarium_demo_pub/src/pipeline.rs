@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use alvarium_annotator::{constants, derive_hash, Annotation};
+use alvarium_sdk_rust::config::Signable;
+use alvarium_sdk_rust::factories::new_hash_provider;
+use alvarium_sdk_rust::sdk::SDK;
+use serde::{Deserialize, Serialize};
+use streams::transport::utangle::Client;
+use streams::{Address, User};
+use tokio::sync::Mutex;
+use crate::BASE_TOPIC;
+use crate::annotation_publisher::{self, AnnotationPublisher};
+use crate::codec::{encode_message, ContentEncoding, MessageKind, PayloadEncoding};
+use crate::config::{BatchConfig, PublisherConfig};
+use crate::metrics::METRICS;
+use crate::mock_sensor::{SensorDescription, SensorReading};
+use crate::sensor_keys::SensorKeys;
+use crate::topics::Topics;
+
+/// A reading pushed by an external device, along with the serialised reading and signature exactly
+/// as the device signed them, if it did
+pub struct IngestedReading {
+    pub reading: SensorReading,
+    pub signed: Option<Signable>,
+}
+
+/// A reading serialised once, so that the bytes that are signed, annotated and published are the same
+pub struct SignedReading {
+    pub reading: SensorReading,
+    /// The serialised reading as seed, along with its signature
+    pub signable: Signable,
+}
+
+impl SignedReading {
+    /// Serialise a reading and sign the serialised bytes
+    pub fn new(reading: SensorReading, sign: impl FnOnce(&[u8]) -> String) -> Self {
+        let seed = serde_json::to_string(&reading).unwrap();
+        let signature = sign(seed.as_bytes());
+        SignedReading { reading, signable: Signable::new(seed, signature) }
+    }
+
+    /// Serialise a reading that no one signed, it is published with an empty signature so that no
+    /// signature check can be satisfied by it
+    pub fn unsigned(reading: SensorReading) -> Self {
+        let seed = serde_json::to_string(&reading).unwrap();
+        SignedReading { reading, signable: Signable::new(seed, String::new()) }
+    }
+}
+
+/// Several readings of one sensor sent as a single stream message
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReadingBatch {
+    pub sensor: String,
+    /// Hashing algorithm of the reading hashes, the one the annotators derive their keys with
+    pub hash_type: String,
+    pub readings: Vec<BatchedReading>,
+}
+
+/// A reading within a batch, kept in the serialised form it was signed and annotated in so that its
+/// hash can be checked against the annotation keys
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchedReading {
+    pub hash: String,
+    pub payload: String,
+    pub signature: String,
+}
+
+/// Annotations of a reading along with a reference to the message the reading was published in
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AnnotationBundle {
+    pub reading: ReadingLink,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Identifies a published reading: the hex encoded blake2b hash of the address of the message it was
+/// sent in, as used by the explorer, and the hash of the serialised reading, which tells readings
+/// of a batch apart
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReadingLink {
+    pub address: String,
+    pub hash: String,
+}
+
+/// Snapshot of the sensor key registry, sent whenever it changes so subscribers can audit which key
+/// vouched for the readings of a sensor at any point in time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SensorKeyRegistry {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Sensor id -> hex encoded public key
+    pub keys: BTreeMap<String, String>,
+}
+
+/// Periodic liveness message of the publisher
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub uptime_secs: u64,
+    /// Time between two heartbeats, lets subscribers tell when the publisher went quiet
+    pub interval_secs: u64,
+    /// Number of sensors that produced a reading since the previous heartbeat
+    pub sensors_active: usize,
+    pub sensors: Vec<SensorStatus>,
+    /// Readings received but not yet sent on the stream
+    pub outbox_depth: usize,
+}
+
+/// Last reading published for a sensor
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SensorStatus {
+    pub id: String,
+    pub last_reading: chrono::DateTime<chrono::Utc>,
+    pub last_value: u8,
+}
+
+/// A reading waiting in a batch for the window to close
+struct PendingReading {
+    signed: SignedReading,
+    queued: Instant,
+}
+
+/// Publishes readings on their sensor branch and annotates them through the sdk, which publishes the
+/// annotations on the annotation branch
+pub struct Pipeline<'a> {
+    author: Arc<Mutex<User<Client>>>,
+    topics: Arc<Mutex<Topics>>,
+    sensors: Arc<Mutex<Vec<SensorDescription>>>,
+    sdk: SDK<'a, AnnotationPublisher>,
+    /// Annotators configured in the sdk, counted in the metrics for every annotated reading
+    annotator_kinds: Vec<String>,
+    annotation_topic: String,
+    /// Public keys the reading signatures are verified against
+    sensor_keys: Arc<RwLock<SensorKeys>>,
+    /// Hashing algorithm used for the batched reading hashes
+    hash: constants::HashType,
+    batch: BatchConfig,
+    /// Serialisation format of the messages sent by the publisher
+    encoding: PayloadEncoding,
+    /// Compression applied to the messages sent by the publisher
+    compression: ContentEncoding,
+    /// Sensor id -> readings waiting to be sent in a batch
+    pending: HashMap<String, Vec<PendingReading>>,
+    /// Sensor id -> last reading received
+    last_readings: HashMap<String, SensorReading>,
+    started: Instant,
+    /// Whether the stream user changed since the last backup, set whenever a message goes out
+    sent: AtomicBool,
+}
+
+impl<'a> Pipeline<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        author: Arc<Mutex<User<Client>>>,
+        topics: Arc<Mutex<Topics>>,
+        sensors: Arc<Mutex<Vec<SensorDescription>>>,
+        sdk: SDK<'a, AnnotationPublisher>,
+        annotator_kinds: Vec<String>,
+        annotation_topic: String,
+        sensor_keys: Arc<RwLock<SensorKeys>>,
+        hash: constants::HashType,
+        config: &PublisherConfig,
+    ) -> Self {
+        Pipeline {
+            author,
+            topics,
+            sensors,
+            sdk,
+            annotator_kinds,
+            annotation_topic,
+            sensor_keys,
+            hash,
+            batch: config.batch.clone(),
+            encoding: config.encoding,
+            compression: config.compression,
+            pending: HashMap::new(),
+            last_readings: HashMap::new(),
+            started: Instant::now(),
+            sent: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether anything was sent on the stream since the last call, meaning the stream user state has
+    /// to be backed up again
+    pub fn take_sent(&self) -> bool {
+        self.sent.swap(false, Ordering::Relaxed)
+    }
+
+    /// Send a signed reading on its sensor branch and annotate it. When batching is enabled the
+    /// reading is queued until its sensor batch is full or the window expires
+    pub async fn publish(&mut self, signed: SignedReading) {
+        let sensor = signed.reading.id.clone();
+        self.ensure_topic(&sensor).await;
+        log::info!("Sensor {} reading: {}", sensor, signed.reading.value);
+        self.last_readings.insert(sensor.clone(), signed.reading.clone());
+
+        if self.batch.enabled {
+            let pending = self.pending.entry(sensor.clone()).or_default();
+            pending.push(PendingReading { signed, queued: Instant::now() });
+            if pending.len() >= self.batch.max_readings {
+                self.flush(&sensor).await;
+            }
+            return
+        }
+
+        // The signable is published as is, so the subscriber receives the exact annotated bytes
+        if let Some(address) = self.send(&sensor, MessageKind::Reading, &signed.signable).await {
+            METRICS.reading_published();
+            match self.content_hash(&signed.signable.seed) {
+                Some(hash) => {
+                    let link = ReadingLink { address: hex::encode(address.to_blake2b()), hash };
+                    self.annotate(&signed, link).await;
+                },
+                None => METRICS.annotation_failed(),
+            }
+        }
+    }
+
+    /// Send a heartbeat on the base branch describing the state of the publisher. `queued` is the
+    /// number of readings waiting to enter the pipeline.
+    pub async fn heartbeat(&self, interval: Duration, queued: usize) {
+        let now = chrono::Utc::now();
+        let since = now - chrono::Duration::from_std(interval).unwrap_or_else(|_| chrono::Duration::zero());
+        let mut sensors = self.last_readings.values()
+            .map(|reading| SensorStatus {
+                id: reading.id.clone(),
+                last_reading: reading.timestamp,
+                last_value: reading.value,
+            })
+            .collect::<Vec<SensorStatus>>();
+        sensors.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let heartbeat = Heartbeat {
+            timestamp: now,
+            uptime_secs: self.started.elapsed().as_secs(),
+            interval_secs: interval.as_secs(),
+            sensors_active: sensors.iter().filter(|s| s.last_reading >= since).count(),
+            sensors,
+            outbox_depth: queued + self.pending.values().map(|p| p.len()).sum::<usize>(),
+        };
+        log::info!("Sending heartbeat, {} sensors active", heartbeat.sensors_active);
+        self.send(BASE_TOPIC, MessageKind::Heartbeat, &heartbeat).await;
+    }
+
+    /// Send the batches whose oldest reading has been waiting for longer than the window
+    pub async fn flush_expired(&mut self) {
+        let window = Duration::from_secs(self.batch.max_wait_secs);
+        let expired = self.pending.iter()
+            .filter(|(_, pending)| pending.first().map(|p| p.queued.elapsed() >= window).unwrap_or(false))
+            .map(|(sensor, _)| sensor.clone())
+            .collect::<Vec<String>>();
+        for sensor in expired {
+            self.flush(&sensor).await;
+        }
+    }
+
+    /// Send the pending readings of a sensor as a single message, then annotate each of them
+    async fn flush(&mut self, sensor: &str) {
+        let pending = match self.pending.remove(sensor) {
+            Some(pending) if !pending.is_empty() => pending,
+            _ => return,
+        };
+
+        let mut readings = Vec::new();
+        for p in &pending {
+            let hash = match self.content_hash(&p.signed.signable.seed) {
+                Some(hash) => hash,
+                None => {
+                    // The hash provider comes from the configuration, retrying would fail the same way
+                    METRICS.send_failed();
+                    log::error!("Dropped batch of {} readings for {}, they could not be hashed", pending.len(), sensor);
+                    return
+                },
+            };
+            readings.push(BatchedReading {
+                hash,
+                payload: p.signed.signable.seed.clone(),
+                signature: p.signed.signable.signature.clone(),
+            });
+        }
+
+        log::info!("Sending batch of {} readings for {}", pending.len(), sensor);
+        let batch = ReadingBatch { sensor: sensor.to_string(), hash_type: self.hash.0.clone(), readings };
+        if let Some(address) = self.send(sensor, MessageKind::ReadingBatch, &batch).await {
+            let address = hex::encode(address.to_blake2b());
+            for (p, batched) in pending.into_iter().zip(batch.readings) {
+                METRICS.reading_published();
+                let link = ReadingLink { address: address.clone(), hash: batched.hash };
+                self.annotate(&p.signed, link).await;
+            }
+        }
+    }
+
+    /// Hash of a serialised reading with the configured hashing algorithm, the same one the
+    /// annotators derive their keys with
+    fn content_hash(&self, payload: &str) -> Option<String> {
+        match new_hash_provider(&self.hash) {
+            Ok(hasher) => Some(derive_hash(hasher, payload.as_bytes())),
+            Err(e) => {
+                log::error!("Failed to create hash provider: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Wrap a body in an envelope, encode and send it on a branch, recording the outcome in the metrics
+    /// that readiness is judged on. Returns the address of the message if it was sent.
+    async fn send<T: Serialize>(&self, topic: &str, kind: MessageKind, body: &T) -> Option<Address> {
+        let payload = match encode_message(kind, body, self.encoding, self.compression) {
+            Ok(payload) => payload,
+            Err(e) => {
+                METRICS.send_failed();
+                log::error!("Failed to encode message for {}: {}", topic, e);
+                return None
+            }
+        };
+
+        let sent = self.author.lock().await.message()
+            .with_topic(topic)
+            .with_payload(payload.as_slice())
+            .signed()
+            .send()
+            .await;
+        match sent {
+            Ok(sent) => {
+                METRICS.send_succeeded();
+                self.sent.store(true, Ordering::Relaxed);
+                self.topics.lock().await.sent(topic, sent.address().to_string());
+                Some(sent.address())
+            },
+            Err(e) => {
+                METRICS.send_failed();
+                log::error!("Failed to send message for {}: {}", topic, e);
+                None
+            }
+        }
+    }
+
+    /// Annotate a reading through the sdk, which runs every annotator over the signable and publishes
+    /// the resulting annotations on the annotation branch, linked to the message the reading was sent in
+    async fn annotate(&mut self, signed: &SignedReading, link: ReadingLink) {
+        annotation_publisher::link_next(link);
+        match self.sdk.create(signed.signable.to_bytes().as_slice()).await {
+            Ok(_) => self.annotator_kinds.iter().for_each(|kind| METRICS.annotation_created(kind)),
+            Err(e) => {
+                METRICS.annotation_failed();
+                log::error!("Failed to create annotations for {}: {}", signed.reading.id, e);
+            }
+        }
+    }
+
+    /// Send a snapshot of the sensor key registry on the annotation branch
+    pub async fn publish_sensor_keys(&self) {
+        let registry = SensorKeyRegistry {
+            timestamp: chrono::Utc::now(),
+            keys: self.sensor_keys.read().unwrap().keys().clone(),
+        };
+        log::info!("Sending sensor key registry with {} keys", registry.keys.len());
+        self.send(&self.annotation_topic, MessageKind::SensorKeys, &registry).await;
+    }
+
+    /// Create a branch for a data source the first time a reading is seen for it
+    async fn ensure_topic(&self, sensor: &str) {
+        if self.topics.lock().await.contains(sensor) {
+            return
+        }
+
+        match self.author.lock().await.new_branch(BASE_TOPIC, sensor).await {
+            Ok(branch) => {
+                METRICS.send_succeeded();
+                self.sent.store(true, Ordering::Relaxed);
+                log::info!("New branch created for {}: {}", sensor, branch.address());
+                let mut topics = self.topics.lock().await;
+                topics.register(sensor, Some(chrono::Utc::now()));
+                topics.sent(sensor, branch.address().to_string());
+                topics.save();
+                self.sensors.lock().await.push(SensorDescription {
+                    id: sensor.to_string(),
+                    topic: sensor.to_string(),
+                    description: "External device".to_string(),
+                });
+            },
+            Err(e) => {
+                METRICS.send_failed();
+                log::error!("Failed to create branch for {}: {}", sensor, e);
+            }
+        }
+    }
+}
+
+/// Sensor ids become branch names, so they are restricted to a conservative character set, and
+/// readings claiming to come from the future are refused
+pub fn validate_reading(reading: &SensorReading) -> Result<(), String> {
+    if reading.id.is_empty() || reading.id.len() > 64 {
+        return Err("Sensor id must be between 1 and 64 characters".to_string())
+    }
+    if !reading.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err("Sensor id may only contain alphanumeric characters, '_' and '-'".to_string())
+    }
+    if reading.timestamp > chrono::Utc::now() + chrono::Duration::minutes(1) {
+        return Err("Reading timestamp is in the future".to_string())
+    }
+    Ok(())
+}
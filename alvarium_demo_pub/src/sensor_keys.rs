@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+
+/// File mapping sensor ids to the hex encoded ed25519 public key their readings are verified against
+pub const SENSOR_KEYS_PATH: &'static str = "config/sensor_keys.json";
+
+/// Registry of the public key of each sensor. Keys of real devices are added by editing the file,
+/// which is reloaded whenever it changes on disk.
+#[derive(Clone, Default)]
+pub struct SensorKeys {
+    keys: BTreeMap<String, String>,
+    /// Modification time of the file when it was last read or written
+    modified: Option<SystemTime>,
+}
+
+impl SensorKeys {
+    /// Read the registry from disk, or start an empty one if none was saved yet
+    pub fn load() -> Self {
+        let keys = read().unwrap_or_else(|e| {
+            log::warn!("Failed to parse sensor keys, starting with an empty registry: {}", e);
+            BTreeMap::new()
+        });
+        SensorKeys { keys, modified: modified() }
+    }
+
+    pub fn save(&mut self) {
+        fs::write(SENSOR_KEYS_PATH, serde_json::to_vec_pretty(&self.keys).unwrap()).unwrap();
+        self.modified = modified();
+    }
+
+    /// Read the registry again if the file was modified since it was last read, returns true if the
+    /// keys changed. A file that cannot be parsed, such as one caught halfway through an edit, leaves
+    /// the keys as they were until it is modified again.
+    pub fn reload(&mut self) -> bool {
+        let current = modified();
+        if current == self.modified {
+            return false
+        }
+        self.modified = current;
+        let keys = match read() {
+            Ok(keys) => keys,
+            Err(e) => {
+                log::warn!("Failed to parse sensor keys, keeping the {} previous keys: {}", self.keys.len(), e);
+                return false
+            }
+        };
+        let changed = keys != self.keys;
+        self.keys = keys;
+        if changed {
+            log::info!("Reloaded {} sensor keys", self.keys.len());
+        }
+        changed
+    }
+
+    /// Set the key of a sensor, returns true if it was not already registered with that key
+    pub fn register(&mut self, sensor: &str, public_key: String) -> bool {
+        self.keys.insert(sensor.to_string(), public_key.clone()) != Some(public_key)
+    }
+
+    pub fn get(&self, sensor: &str) -> Option<&String> {
+        self.keys.get(sensor)
+    }
+
+    pub fn keys(&self) -> &BTreeMap<String, String> {
+        &self.keys
+    }
+}
+
+/// Keys saved in the registry file, none if there is no file yet
+fn read() -> Result<BTreeMap<String, String>, serde_json::Error> {
+    match fs::read(SENSOR_KEYS_PATH) {
+        Ok(bytes) => serde_json::from_slice(&bytes),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+fn modified() -> Option<SystemTime> {
+    fs::metadata(SENSOR_KEYS_PATH).and_then(|meta| meta.modified()).ok()
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs;
+use crypto::signatures::ed25519::{PublicKey, PUBLIC_KEY_LENGTH};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use streams::id::{Identifier, Psk, PskId};
+use crate::PSK_SEED;
+
+/// File the subscriber registry is persisted to, next to the `user.bin` backup
+pub const SUBSCRIPTIONS_PATH: &'static str = "subscriptions.bin";
+
+/// Registry of the identities that have been granted access to each topic through a keyload
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Subscriptions {
+    /// Topic name -> hex encoded public keys of the subscribers that were keyloaded on it
+    topics: HashMap<String, Vec<String>>,
+    /// Hex encoded id of the pre shared key currently in use, unset until the first rotation. Only the
+    /// id is kept here, the key itself is held by the stream user and saved with its encrypted backup.
+    #[serde(default)]
+    psk_id: Option<String>,
+    /// Hex encoded public keys of the sdk stream users allowed to write annotations
+    #[serde(default)]
+    writers: Vec<String>,
+}
+
+impl Subscriptions {
+    /// Restore the registry from disk, or start an empty one if none was saved yet
+    pub fn load() -> Self {
+        match fs::read(SUBSCRIPTIONS_PATH) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                log::warn!("Failed to parse subscriptions, starting with an empty registry: {}", e);
+                Subscriptions::default()
+            }),
+            Err(_) => Subscriptions::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        fs::write(SUBSCRIPTIONS_PATH, serde_json::to_vec(self).unwrap()).unwrap();
+    }
+
+    /// Record a subscriber on a topic, returns false if it was already present
+    pub fn add(&mut self, topic: &str, identifier: &str) -> bool {
+        let subscribers = self.topics.entry(topic.to_string()).or_default();
+        if subscribers.iter().any(|s| s.eq(identifier)) {
+            return false
+        }
+        subscribers.push(identifier.to_string());
+        true
+    }
+
+    /// Allow a subscriber to write annotations, returns false if it already was
+    pub fn add_writer(&mut self, identifier: &str) -> bool {
+        if self.is_writer(identifier) {
+            return false
+        }
+        self.writers.push(identifier.to_string());
+        true
+    }
+
+    pub fn is_writer(&self, identifier: &str) -> bool {
+        self.writers.iter().any(|w| w.eq(identifier))
+    }
+
+    /// Remove a subscriber from every topic it was granted, returning the topics that were affected
+    pub fn remove(&mut self, identifier: &str) -> Vec<String> {
+        self.writers.retain(|w| !w.eq(identifier));
+        let mut affected = Vec::new();
+        for (topic, subscribers) in self.topics.iter_mut() {
+            let before = subscribers.len();
+            subscribers.retain(|s| !s.eq(identifier));
+            if subscribers.len() != before {
+                affected.push(topic.clone());
+            }
+        }
+        affected
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.keys().cloned().collect()
+    }
+
+    /// Identities of the current subscribers of a topic
+    pub fn subscribers(&self, topic: &str) -> Vec<Identifier> {
+        self.topics.get(topic)
+            .map(|subscribers| subscribers.iter().filter_map(|s| parse_subscriber(s)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Topics a subscriber was granted, without removing it from them
+    pub fn topics_of(&self, identifier: &str) -> Vec<String> {
+        self.topics.iter()
+            .filter(|(_, subscribers)| subscribers.iter().any(|s| s.eq(identifier)))
+            .map(|(topic, _)| topic.clone())
+            .collect()
+    }
+
+    /// Identities of the subscribers of a topic, leaving one of them out
+    pub fn subscribers_except(&self, topic: &str, identifier: &str) -> Vec<Identifier> {
+        self.topics.get(topic)
+            .map(|subscribers| subscribers.iter()
+                .filter(|s| !s.eq(&identifier))
+                .filter_map(|s| parse_subscriber(s))
+                .collect())
+            .unwrap_or_default()
+    }
+
+    /// Id of the pre shared key currently in use for keyloads. Until the first rotation this is the
+    /// key derived from the shared seed, so existing subscribers keep working.
+    pub fn psk_id(&self) -> PskId {
+        self.psk_id.as_deref()
+            .and_then(psk_id_from_hex)
+            .unwrap_or_else(|| Psk::from_seed(PSK_SEED).to_pskid())
+    }
+
+    /// Record the pre shared key that keyloads are issued with from now on
+    pub fn set_psk_id(&mut self, psk_id: PskId) {
+        self.psk_id = Some(hex::encode(psk_id));
+    }
+}
+
+/// Fresh pre shared key made of random bytes, so that it cannot be derived from anything public
+pub fn generate_psk() -> Psk {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+    Psk::new(bytes)
+}
+
+fn psk_id_from_hex(psk_id: &str) -> Option<PskId> {
+    let bytes: [u8; 16] = hex::decode(psk_id).ok()?.try_into().ok()?;
+    Some(PskId::new(bytes))
+}
+
+/// Identity of a registered subscriber, an entry that cannot be parsed is left out of keyloads
+fn parse_subscriber(identifier: &str) -> Option<Identifier> {
+    let parsed = identifier_from_hex(identifier);
+    if parsed.is_none() {
+        log::warn!("Registered subscriber {} is not a hex encoded ed25519 public key, leaving it out", identifier);
+    }
+    parsed
+}
+
+/// Rebuild a streams identifier from the hex encoded ed25519 public key stored in the registry
+pub fn identifier_from_hex(identifier: &str) -> Option<Identifier> {
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = hex::decode(identifier).ok()?.try_into().ok()?;
+    PublicKey::try_from_bytes(bytes).ok().map(Identifier::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto::signatures::ed25519::SecretKey;
+    use streams::id::Identifier;
+    use super::*;
+
+    #[test]
+    fn identifier_round_trips_through_hex() {
+        let key = SecretKey::generate().unwrap();
+        let identifier = Identifier::from(key.public_key());
+        assert_eq!(identifier_from_hex(&identifier.to_string()), Some(identifier));
+    }
+
+    #[test]
+    fn subscribers_are_kept_until_removed() {
+        let key = SecretKey::generate().unwrap();
+        let identifier = Identifier::from(key.public_key()).to_string();
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.add("sensor", &identifier));
+        assert!(!subscriptions.add("sensor", &identifier));
+
+        assert_eq!(subscriptions.topics_of(&identifier), vec!["sensor".to_string()]);
+        assert!(subscriptions.subscribers_except("sensor", &identifier).is_empty());
+        assert_eq!(subscriptions.subscribers("sensor").len(), 1);
+
+        assert_eq!(subscriptions.remove(&identifier), vec!["sensor".to_string()]);
+        assert!(subscriptions.subscribers("sensor").is_empty());
+    }
+
+    #[test]
+    fn writers_are_revoked_with_their_subscription() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.add("annotations", "writer");
+        assert!(subscriptions.add_writer("writer"));
+        assert!(!subscriptions.add_writer("writer"));
+        assert!(subscriptions.is_writer("writer"));
+        assert!(!subscriptions.is_writer("reader"));
+
+        subscriptions.remove("writer");
+        assert!(!subscriptions.is_writer("writer"));
+    }
+
+    #[test]
+    fn rotated_psk_is_not_derived_from_the_seed() {
+        let mut subscriptions = Subscriptions::default();
+        let seeded = subscriptions.psk_id();
+        assert_eq!(seeded, Psk::from_seed(PSK_SEED).to_pskid());
+
+        let psk_id = generate_psk().to_pskid();
+        subscriptions.set_psk_id(psk_id);
+        assert_ne!(subscriptions.psk_id(), seeded);
+        assert_eq!(subscriptions.psk_id(), psk_id);
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// File the topic registry is persisted to, next to the `user.bin` backup
+pub const TOPICS_PATH: &'static str = "topics.bin";
+
+/// Details of a branch of the stream
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TopicInfo {
+    pub name: String,
+    /// Time the branch was created, unknown for branches created before the registry existed
+    pub created: Option<DateTime<Utc>>,
+    /// Address of the last message sent on the branch
+    pub last_message: Option<String>,
+}
+
+/// Registry of the branches of the stream
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Topics(Vec<TopicInfo>);
+
+impl Topics {
+    /// Restore the registry from disk, or start an empty one if none was saved yet
+    pub fn load() -> Self {
+        match fs::read(TOPICS_PATH) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                log::warn!("Failed to parse topics, starting with an empty registry: {}", e);
+                Topics::default()
+            }),
+            Err(_) => Topics::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        fs::write(TOPICS_PATH, serde_json::to_vec(self).unwrap()).unwrap();
+    }
+
+    /// Register a branch, `created` is only set when the branch is new rather than restored
+    pub fn register(&mut self, name: &str, created: Option<DateTime<Utc>>) {
+        if !self.contains(name) {
+            self.0.push(TopicInfo { name: name.to_string(), created, last_message: None });
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|topic| topic.name.eq(name))
+    }
+
+    /// Record the address of the latest message sent on a branch
+    pub fn sent(&mut self, name: &str, address: String) {
+        if let Some(topic) = self.0.iter_mut().find(|topic| topic.name.eq(name)) {
+            topic.last_message = Some(address);
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|topic| topic.name.clone()).collect()
+    }
+
+    pub fn list(&self) -> &[TopicInfo] {
+        &self.0
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::Read;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::errors::{Error, Result};
+
+/// Prefix of every payload sent by the publisher. It is followed by a byte identifying the
+/// `PayloadEncoding` of the envelope and a byte identifying the compression applied to it.
+/// Legacy payloads are plain json and never start with it.
+pub const FRAME_MARKER: &'static [u8] = b"ALV";
+
+/// Serialisation format of the message envelope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadEncoding {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+/// Common fields of every envelope
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnvelopeHeader {
+    /// Message type, used to look up the handler of the message
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Version of the envelope layout
+    pub version: u8,
+    /// Identifier of the body schema
+    pub schema: String,
+}
+
+/// A decompressed envelope along with the encoding needed to deserialise it
+pub struct Frame {
+    pub encoding: PayloadEncoding,
+    pub envelope: Vec<u8>,
+}
+
+impl Frame {
+    /// Envelope header, read without deserialising the body
+    pub fn header(&self) -> Result<EnvelopeHeader> {
+        self.deserialise::<EnvelopeHeader>()
+    }
+
+    /// Deserialise the envelope body
+    pub fn body<T: DeserializeOwned>(&self) -> Result<T> {
+        #[derive(Deserialize)]
+        struct Envelope<T> {
+            body: T,
+        }
+
+        Ok(self.deserialise::<Envelope<T>>()?.body)
+    }
+
+    fn deserialise<T: DeserializeOwned>(&self) -> Result<T> {
+        match self.encoding {
+            PayloadEncoding::Json => serde_json::from_slice(&self.envelope)
+                .map_err(|e| Error::SerialisationError(e.to_string())),
+            PayloadEncoding::Cbor => ciborium::de::from_reader(self.envelope.as_slice())
+                .map_err(|e| Error::SerialisationError(e.to_string())),
+            PayloadEncoding::MessagePack => rmp_serde::from_slice(&self.envelope)
+                .map_err(|e| Error::SerialisationError(e.to_string())),
+        }
+    }
+}
+
+/// Strip the frame header and decompress the envelope, legacy payloads without the header are
+/// returned as `None`
+pub fn decode_frame(payload: &[u8]) -> Result<Option<Frame>> {
+    let framed = match payload.strip_prefix(FRAME_MARKER) {
+        Some(framed) if framed.len() >= 2 => framed,
+        _ => return Ok(None),
+    };
+
+    let encoding = match framed[0] {
+        0 => PayloadEncoding::Json,
+        1 => PayloadEncoding::Cbor,
+        2 => PayloadEncoding::MessagePack,
+        other => return Err(Error::UnknownPayloadEncoding(other)),
+    };
+
+    let compressed = &framed[2..];
+    let mut envelope = Vec::new();
+    match framed[1] {
+        0 => envelope.extend_from_slice(compressed),
+        1 => {
+            flate2::read::DeflateDecoder::new(compressed)
+                .read_to_end(&mut envelope)
+                .map_err(Error::CompressionError)?;
+        },
+        2 => envelope = zstd::decode_all(compressed).map_err(Error::CompressionError)?,
+        other => return Err(Error::UnknownContentEncoding(other)),
+    }
+    Ok(Some(Frame { encoding, envelope }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Body {
+        id: String,
+        values: Vec<u8>,
+    }
+
+    #[derive(Serialize)]
+    struct Envelope {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        version: u8,
+        schema: &'static str,
+        body: Body,
+    }
+
+    fn body() -> Body {
+        Body { id: "Flow_Sensor_1".to_string(), values: vec![180, 190, 200] }
+    }
+
+    /// A payload framed the way the publisher frames it
+    fn frame(encoding: u8, compression: u8) -> Vec<u8> {
+        let envelope = Envelope { kind: "reading", version: 1, schema: "test.v1", body: body() };
+        let serialised = match encoding {
+            0 => serde_json::to_vec(&envelope).unwrap(),
+            1 => {
+                let mut serialised = Vec::new();
+                ciborium::ser::into_writer(&envelope, &mut serialised).unwrap();
+                serialised
+            },
+            _ => rmp_serde::to_vec_named(&envelope).unwrap(),
+        };
+        let compressed = match compression {
+            0 => serialised,
+            1 => {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&serialised).unwrap();
+                encoder.finish().unwrap()
+            },
+            _ => zstd::encode_all(serialised.as_slice(), 0).unwrap(),
+        };
+
+        let mut payload = FRAME_MARKER.to_vec();
+        payload.push(encoding);
+        payload.push(compression);
+        payload.extend(compressed);
+        payload
+    }
+
+    #[test]
+    fn every_encoding_and_compression_decodes() {
+        let encodings = [PayloadEncoding::Json, PayloadEncoding::Cbor, PayloadEncoding::MessagePack];
+        for (encoding_marker, encoding) in encodings.into_iter().enumerate() {
+            for compression in 0..3 {
+                let frame = decode_frame(&frame(encoding_marker as u8, compression)).unwrap().unwrap();
+                assert_eq!(frame.encoding, encoding);
+
+                // The header is read on its own, the body is left aside
+                let header = frame.header().unwrap();
+                assert_eq!(header.kind, "reading");
+                assert_eq!(header.version, 1);
+                assert_eq!(header.schema, "test.v1");
+
+                assert_eq!(frame.body::<Body>().unwrap(), body());
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_payloads_are_not_frames() {
+        let legacy = serde_json::to_vec(&body()).unwrap();
+        assert!(decode_frame(&legacy).unwrap().is_none());
+        // A marker without the encoding bytes is not a frame either
+        assert!(decode_frame(FRAME_MARKER).unwrap().is_none());
+    }
+
+    #[test]
+    fn unknown_markers_are_rejected() {
+        let mut payload = frame(0, 0);
+        payload[FRAME_MARKER.len()] = 7;
+        assert!(matches!(decode_frame(&payload), Err(Error::UnknownPayloadEncoding(7))));
+
+        let mut payload = frame(0, 0);
+        payload[FRAME_MARKER.len() + 1] = 7;
+        assert!(matches!(decode_frame(&payload), Err(Error::UnknownContentEncoding(7))));
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+use crate::records::RetentionPolicy;
+
+/// Path of the subscriber configuration, read when the subscriber starts
+pub const SUBSCRIBER_CONFIG_PATH: &'static str = "config/subscriber.json";
+
+/// Options of the demo subscriber
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriberConfig {
+    /// Hex encoded public key of the publisher annotations are verified against. When unset, the key
+    /// advertised by the publisher stream metadata is trusted.
+    #[serde(default)]
+    pub publisher_public_key: Option<String>,
+    /// Bounds of the readings kept in memory for the dashboard
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+}
+
+impl SubscriberConfig {
+    /// Load the subscriber configuration, falling back to the defaults if it is missing
+    pub fn load() -> Self {
+        match std::fs::read(SUBSCRIBER_CONFIG_PATH) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap(),
+            Err(_) => SubscriberConfig::default(),
+        }
+    }
+}
@@ -11,6 +11,33 @@ pub enum Error {
 
     #[error("Alvarium error: {0}")]
     AlvariumSdkError(alvarium_sdk_rust::errors::Error),
+
+    #[error("Compression error: {0}")]
+    CompressionError(std::io::Error),
+    #[error("Unknown content encoding: {0}")]
+    UnknownContentEncoding(u8),
+    #[error("Unknown payload encoding: {0}")]
+    UnknownPayloadEncoding(u8),
+    #[error("Serialisation error: {0}")]
+    SerialisationError(String),
+    #[error("No handler registered for message type: {0}")]
+    UnknownMessageType(String),
+    #[error("Unsupported envelope version for {0}: {1}")]
+    UnsupportedEnvelopeVersion(String, u8),
+
+    #[error("Stream error: {0}")]
+    StreamError(String),
+
+    #[error("Store error: {0}")]
+    StoreError(sled::Error),
+    #[error("Store migration error: {0}")]
+    StoreMigrationError(String),
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::StoreError(e)
+    }
 }
 
 impl From<alvarium_annotator::Error> for Error {
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use alvarium_annotator::{derive_hash, Annotation, HashProvider};
+use alvarium_annotator::constants::HashType;
+use alvarium_sdk_rust::config::Signable;
+use alvarium_sdk_rust::factories::new_hash_provider;
+use alvarium_sdk_rust::providers::hash_provider::Sha256Provider;
+use rocket::tokio::sync::Mutex;
+use crate::codec::Frame;
+use crate::errors::{Error, Result};
+use crate::records::Records;
+use crate::store::Store;
+use crate::verification::AnnotationVerifier;
+use crate::{
+    AnnotationBundle, AnnotationWrap, Heartbeat, ReadingBatch, ReadingLink, ReadingWrap, SensorKeyRegistry, SensorReading,
+};
+
+/// Message types produced by the publisher
+pub const READING: &'static str = "reading";
+pub const READING_BATCH: &'static str = "reading_batch";
+pub const ANNOTATIONS: &'static str = "annotations";
+pub const HEARTBEAT: &'static str = "heartbeat";
+pub const SENSOR_KEYS: &'static str = "sensor_keys";
+
+/// Schema of reading messages carrying the serialised reading exactly as it was signed and annotated
+pub const SIGNED_READING_SCHEMA: &'static str = "alvarium.demo.signed_reading.v1";
+
+/// Processes the enveloped messages of one type
+#[rocket::async_trait]
+pub trait MessageHandler: Send + Sync {
+    /// Envelope versions the handler knows how to read
+    fn supports(&self, version: u8) -> bool {
+        version == 1
+    }
+
+    async fn handle(&self, frame: &Frame, address: &str) -> Result<()>;
+}
+
+/// Message type -> handler, new message kinds are supported by registering a handler for them
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Box<dyn MessageHandler>>,
+}
+
+impl HandlerRegistry {
+    /// Registry with the handlers for readings, reading batches, annotations, heartbeats and sensor
+    /// key registries
+    pub fn with_defaults(
+        records: Arc<Mutex<Records>>,
+        heartbeat: Arc<Mutex<Option<Heartbeat>>>,
+        verifier: Arc<AnnotationVerifier>,
+        store: Store,
+    ) -> Self {
+        let mut registry = HandlerRegistry::default();
+        registry.register(READING, Box::new(ReadingHandler { records: records.clone(), store: store.clone() }));
+        registry.register(READING_BATCH, Box::new(ReadingBatchHandler { records: records.clone(), store: store.clone() }));
+        registry.register(ANNOTATIONS, Box::new(AnnotationsHandler { records, store: store.clone(), verifier }));
+        registry.register(HEARTBEAT, Box::new(HeartbeatHandler { heartbeat }));
+        registry.register(SENSOR_KEYS, Box::new(SensorKeysHandler { store }));
+        registry
+    }
+
+    pub fn register(&mut self, kind: &str, handler: Box<dyn MessageHandler>) {
+        self.handlers.insert(kind.to_string(), handler);
+    }
+
+    /// Hand a message over to the handler registered for its type
+    pub async fn dispatch(&self, frame: &Frame, address: &str) -> Result<()> {
+        let header = frame.header()?;
+        let handler = self.handlers.get(&header.kind)
+            .ok_or_else(|| Error::UnknownMessageType(header.kind.clone()))?;
+        if !handler.supports(header.version) {
+            return Err(Error::UnsupportedEnvelopeVersion(header.kind, header.version))
+        }
+        debug!("Dispatching {} message with schema {}", header.kind, header.schema);
+        handler.handle(frame, address).await
+    }
+}
+
+struct ReadingHandler {
+    records: Arc<Mutex<Records>>,
+    store: Store,
+}
+
+#[rocket::async_trait]
+impl MessageHandler for ReadingHandler {
+    async fn handle(&self, frame: &Frame, address: &str) -> Result<()> {
+        let (payload, reading) = if frame.header()?.schema.eq(SIGNED_READING_SCHEMA) {
+            // Annotation keys are derived from the published seed, so it is kept as received rather
+            // than re-serialised
+            let signable = frame.body::<Signable>()?;
+            let reading = serde_json::from_str::<SensorReading>(&signable.seed)
+                .map_err(|e| Error::SerialisationError(e.to_string()))?;
+            (signable.seed, reading)
+        } else {
+            let reading = frame.body::<SensorReading>()?;
+            (serde_json::to_string(&reading).unwrap(), reading)
+        };
+        let id = Sha256Provider::new().derive(payload.as_bytes());
+        info!("Found reading: {}", id);
+        let reading = ReadingWrap { id, reading, address: address.to_string(), batched: false, payload: Some(payload) };
+        self.store.insert_reading(&reading)?;
+        self.records.lock().await.push_reading(reading);
+        Ok(())
+    }
+}
+
+struct ReadingBatchHandler {
+    records: Arc<Mutex<Records>>,
+    store: Store,
+}
+
+#[rocket::async_trait]
+impl MessageHandler for ReadingBatchHandler {
+    async fn handle(&self, frame: &Frame, address: &str) -> Result<()> {
+        let batch = frame.body::<ReadingBatch>()?;
+        unpack_batch(self.records.clone(), &self.store, batch, address.to_string()).await;
+        Ok(())
+    }
+}
+
+struct AnnotationsHandler {
+    records: Arc<Mutex<Records>>,
+    store: Store,
+    verifier: Arc<AnnotationVerifier>,
+}
+
+#[rocket::async_trait]
+impl MessageHandler for AnnotationsHandler {
+    async fn handle(&self, frame: &Frame, _address: &str) -> Result<()> {
+        let bundle = frame.body::<AnnotationBundle>()?;
+        unpack_annotations(self.records.clone(), &self.store, &self.verifier, bundle.annotations, Some(bundle.reading)).await;
+        Ok(())
+    }
+}
+
+struct HeartbeatHandler {
+    heartbeat: Arc<Mutex<Option<Heartbeat>>>,
+}
+
+#[rocket::async_trait]
+impl MessageHandler for HeartbeatHandler {
+    async fn handle(&self, frame: &Frame, _address: &str) -> Result<()> {
+        let heartbeat = frame.body::<Heartbeat>()?;
+        info!("Found heartbeat: uptime {}s, {} sensors active", heartbeat.uptime_secs, heartbeat.sensors_active);
+        let mut latest = self.heartbeat.lock().await;
+        // Messages are not guaranteed to arrive in order across branches
+        if latest.as_ref().map(|hb| hb.timestamp < heartbeat.timestamp).unwrap_or(true) {
+            *latest = Some(heartbeat);
+        }
+        Ok(())
+    }
+}
+
+struct SensorKeysHandler {
+    store: Store,
+}
+
+#[rocket::async_trait]
+impl MessageHandler for SensorKeysHandler {
+    async fn handle(&self, frame: &Frame, _address: &str) -> Result<()> {
+        let registry = frame.body::<SensorKeyRegistry>()?;
+        info!("Found sensor key registry with {} keys", registry.keys.len());
+        self.store.insert_sensor_keys(&registry)
+    }
+}
+
+/// Expands a batch of readings sent as a single message back into individual readings, all sharing
+/// the address of the batch message. Readings whose payload does not match the hash the publisher sent
+/// along are kept but reported, as their annotations cannot be joined to them.
+pub async fn unpack_batch(records: Arc<Mutex<Records>>, store: &Store, batch: ReadingBatch, address: String) {
+    info!("Found batch of {} readings for {}", batch.readings.len(), batch.sensor);
+    let hash = HashType(batch.hash_type);
+    for batched in batch.readings {
+        match serde_json::from_str::<SensorReading>(&batched.payload) {
+            Ok(reading) => {
+                // Annotations are correlated through the payload hashed with their own hash type, so
+                // the id is derived like the id of any other reading
+                let id = Sha256Provider::new().derive(batched.payload.as_bytes());
+                info!("Found reading: {}", id);
+                match new_hash_provider(&hash) {
+                    Ok(hasher) => if derive_hash(hasher, batched.payload.as_bytes()).ne(&batched.hash) {
+                        warn!("Batched reading {} does not match its {} hash {}", id, hash.0, batched.hash);
+                    },
+                    Err(e) => warn!("Cannot check batched reading {} against its {} hash: {}", id, hash.0, e),
+                }
+                let reading = ReadingWrap {
+                    id,
+                    reading,
+                    address: address.clone(),
+                    batched: true,
+                    payload: Some(batched.payload),
+                };
+                if let Err(e) = store.insert_reading(&reading) {
+                    error!("Failed to store reading {}: {}", reading.id, e);
+                }
+                records.lock().await.push_reading(reading)
+            },
+            Err(_) => error!("failed to parse batched reading")
+        }
+    }
+}
+
+/// Store annotations, joined to the linked reading if there is one or to their key otherwise.
+/// Annotations whose signature does not verify are kept but marked as unverified.
+pub async fn unpack_annotations(
+    records: Arc<Mutex<Records>>,
+    store: &Store,
+    verifier: &AnnotationVerifier,
+    items: Vec<Annotation>,
+    link: Option<ReadingLink>,
+) {
+    if items.is_empty() {
+        return
+    }
+    let mut anns = String::new();
+    anns.push_str(&format!("Found annotations for {}: ", items[0].key));
+    for annotation in items {
+        anns.push_str(&format!("{}  ", annotation.kind.0));
+        let (reading_id, reading_address) = match &link {
+            Some(link) => (link.hash.clone(), Some(link.address.clone())),
+            None => (annotation.key.clone(), None),
+        };
+        let verified = verifier.verify(&annotation);
+        if !verified {
+            warn!("Annotation {} of type {} failed signature verification", annotation.id, annotation.kind.0);
+        }
+        let annotation = AnnotationWrap { reading_id, reading_address, verified, annotation };
+        if let Err(e) = store.insert_annotation(&annotation) {
+            error!("Failed to store annotation {}: {}", annotation.annotation.id, e);
+        }
+
+        records.lock().await.push_annotation(annotation)
+    }
+    info!("{}", anns);
+}
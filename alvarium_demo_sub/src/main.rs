@@ -1,26 +1,49 @@
 #[macro_use] extern crate rocket;
 
+pub mod codec;
+pub mod config;
 pub mod errors;
+pub mod handlers;
 pub mod logger;
-
-use std::collections::HashSet;
+pub mod records;
+pub mod rescoring;
+pub mod resync;
+pub mod scoring;
+pub mod state;
+pub mod store;
+pub mod verification;
+
+use std::collections::{BTreeMap, HashSet};
 use std::io::Write;
 use std::str::FromStr;
 use std::sync::{Arc};
-use alvarium_annotator::{Annotation, AnnotationList, HashProvider, MessageWrapper};
+use alvarium_annotator::{derive_hash, Annotation, AnnotationList, HashProvider, MessageWrapper};
+use alvarium_annotator::constants::HashType;
+use alvarium_sdk_rust::factories::new_hash_provider;
 use alvarium_sdk_rust::providers::hash_provider::Sha256Provider;
 use base64::Engine;
 use rocket::tokio::{self, sync::Mutex, time::Duration};
 use reqwest;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::{Build, Rocket, State};
+use rocket::serde::json::Json;
 use rocket_dyn_templates::{context, Template};
 use serde::{Deserialize, Serialize};
 use streams::{Address, Message, User};
-use streams::id::{Ed25519, Psk};
+use streams::id::Psk;
 use streams::transport::utangle::Client;
+use crate::config::SubscriberConfig;
+use crate::handlers::HandlerRegistry;
+use crate::records::Records;
+use crate::scoring::{Score, ScoreRecord, ScoringPolicy};
+use crate::store::Store;
+use crate::verification::AnnotationVerifier;
 
 const NODE_URL: &'static str = "http://nodes.02.demia-testing-domain.com:14102";
+/// Api of the publisher, serving the stream metadata and subscription requests
+const PROVIDER_URL: &'static str = "http://localhost:8900";
+/// Sensors without a reading for this long are flagged on the dashboard
+const STALE_SENSOR_SECS: i64 = 120;
 
 
 // Define your Reading and Annotation structs
@@ -28,8 +51,14 @@ const NODE_URL: &'static str = "http://nodes.02.demia-testing-domain.com:14102";
 struct Reading(String);
 
 #[derive(Clone, Serialize, Deserialize)]
-struct AnnotationWrap {
+pub struct AnnotationWrap {
     reading_id: String,
+    /// Address of the message the annotated reading was published in, when the publisher linked it
+    #[serde(default)]
+    reading_address: Option<String>,
+    /// The annotation signature was produced by the publisher key, unverified annotations are not scored
+    #[serde(default)]
+    verified: bool,
     annotation: Annotation
 }
 
@@ -44,12 +73,143 @@ pub struct SensorReading {
 pub struct ReadingWrap {
     id: String,
     address: String,
-    reading: SensorReading
+    reading: SensorReading,
+    /// Whether the reading shares its message with other readings
+    #[serde(default)]
+    batched: bool,
+    /// Serialised reading as published, which annotation keys are derived from
+    #[serde(default)]
+    payload: Option<String>,
+}
+
+impl ReadingWrap {
+    /// Hash of the reading payload with the given algorithm, the key its annotations are expected to
+    /// carry. Readings stored before their payload was kept can only be identified by their id.
+    fn key(&self, hash: &HashType) -> Option<String> {
+        match &self.payload {
+            Some(payload) => match new_hash_provider(hash) {
+                Ok(hasher) => Some(derive_hash(hasher, payload.as_bytes())),
+                Err(e) => {
+                    warn!("Cannot hash reading {} with {}: {}", self.id, hash.0, e);
+                    None
+                }
+            },
+            None => Some(self.id.clone()),
+        }
+    }
+}
+
+/// Annotations of a reading along with a reference to the message the reading was published in
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AnnotationBundle {
+    reading: ReadingLink,
+    annotations: Vec<Annotation>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReadingLink {
+    address: String,
+    hash: String,
+}
+
+/// Several readings of one sensor sent as a single stream message
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReadingBatch {
+    sensor: String,
+    /// Hashing algorithm of the reading hashes
+    hash_type: String,
+    readings: Vec<BatchedReading>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchedReading {
+    hash: String,
+    payload: String,
+    /// Signature of the payload, absent from batches sent before readings were published signed
+    #[serde(default)]
+    signature: String,
+}
+
+/// Snapshot of the publisher sensor key registry
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SensorKeyRegistry {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    /// Sensor id -> hex encoded public key
+    keys: BTreeMap<String, String>,
+}
+
+impl SensorKeyRegistry {
+    /// Key registered for a sensor when a reading was taken, from the latest registry published by then.
+    /// Registries are expected oldest first.
+    fn key_at(registries: &[SensorKeyRegistry], sensor: &str, timestamp: chrono::DateTime<chrono::Utc>) -> Option<String> {
+        registries.iter()
+            .rev()
+            .find(|registry| registry.timestamp <= timestamp)
+            .and_then(|registry| registry.keys.get(sensor).cloned())
+    }
+}
+
+/// Periodic liveness message of the publisher
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    uptime_secs: u64,
+    interval_secs: u64,
+    sensors_active: usize,
+    sensors: Vec<SensorStatus>,
+    outbox_depth: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SensorStatus {
+    id: String,
+    last_reading: chrono::DateTime<chrono::Utc>,
+    last_value: u8,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DashboardContext {
-    sensors: Vec<SensorDashboardContext>
+    publisher: PublisherDashboardContext,
+    sensors: Vec<SensorDashboardContext>,
+    orphans: OrphansDashboardContext,
+}
+
+/// Readings and annotations that could not be correlated with each other
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OrphansDashboardContext {
+    /// Readings whose hash matches no annotation key
+    reading_count: usize,
+    /// Annotations whose key matches no reading
+    annotation_count: usize,
+    readings: Vec<OrphanReadingDashboardContext>,
+    annotations: Vec<OrphanAnnotationDashboardContext>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OrphanReadingDashboardContext {
+    id: String,
+    sensor: String,
+    address: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OrphanAnnotationDashboardContext {
+    key: String,
+    kind: String,
+    hash: String,
+    reading_address: Option<String>,
+    timestamp: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PublisherDashboardContext {
+    /// A heartbeat was received within two heartbeat intervals
+    alive: bool,
+    last_heartbeat: Option<chrono::DateTime<chrono::Utc>>,
+    uptime_secs: u64,
+    sensors_active: usize,
+    outbox_depth: usize,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -57,86 +217,208 @@ pub struct SensorDashboardContext {
     id: String,
     total: usize,
     avgcf: String,
+    last_reading: Option<chrono::DateTime<chrono::Utc>>,
+    /// No reading was seen for longer than `STALE_SENSOR_SECS`
+    stale: bool,
     readings: Vec<ReadingDashboardContext>
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ReadingDashboardContext {
     id: String,
+    /// Untruncated reading id, used to look up the score explanation
+    full_id: String,
     address: String,
     timestamp: chrono::DateTime<chrono::Utc>,
     value: u8,
-    annotations: Vec<Annotation>,
+    annotations: Vec<AnnotationDashboardContext>,
+    /// Public key registered for the sensor when the reading was taken, which its signature is checked
+    /// against
+    signer: Option<String>,
     score: f32,
+    /// How the score was reached
+    explanation: Score,
+}
+
+/// Scores of the readings of a sensor under one version of the scoring policy
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PolicyComparison {
+    version: String,
+    created: chrono::DateTime<chrono::Utc>,
+    /// The policy is the one in effect
+    current: bool,
+    /// Every stored reading was scored under the policy
+    complete: bool,
+    /// Readings of the sensor scored under the policy
+    count: usize,
+    average: f32,
+    /// Most recent readings first
+    readings: Vec<ReadingScoreContext>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReadingScoreContext {
+    id: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    score: f32,
+}
+
+impl PolicyComparison {
+    fn new(policy: scoring::PolicyRecord, current: &str, scores: Vec<ScoreRecord>, limit: usize) -> Self {
+        let count = scores.len();
+        let average = if count > 0 {
+            scores.iter().map(|record| record.score.value).sum::<f32>() / count as f32
+        } else {
+            0.0
+        };
+        let readings = scores.into_iter()
+            .rev()
+            .take(limit)
+            .map(|record| ReadingScoreContext { id: record.reading_id, timestamp: record.timestamp, score: record.score.value })
+            .collect();
+        PolicyComparison {
+            current: policy.version.eq(current),
+            version: policy.version,
+            created: policy.created,
+            complete: policy.complete,
+            count,
+            average,
+            readings,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AnnotationDashboardContext {
+    #[serde(flatten)]
+    annotation: Annotation,
+    verified: bool,
 }
 
 impl DashboardContext {
-    fn new(messages: Vec<ReadingWrap>, annotations: Vec<AnnotationWrap>) -> Self {
-        let mut ids = HashSet::new();
-        messages.iter().for_each(|m| {
-            ids.insert(m.reading.id.clone());
-        });
+    fn new(
+        records: &Records,
+        sensors: Vec<String>,
+        heartbeat: Option<Heartbeat>,
+        registries: &[SensorKeyRegistry],
+    ) -> Self {
+        let now = chrono::Utc::now();
+        // Sensors discovered from the stream metadata are shown even before their first reading
+        let mut ids: HashSet<String> = sensors.into_iter().collect();
+        ids.extend(records.sensors());
 
         debug!("Ids: {:?}", ids);
 
         let mut sensors = Vec::new();
         for id in ids {
-            let mut readings = Vec::new();
-            messages.iter()
-                .filter(|m| m.reading.id.eq(&id))
-                .for_each(|reading| {
-                    let mut score = 0_f32;
-                    let annotations = annotations.iter()
-                        .filter(|ann| ann.reading_id.eq(&reading.id))
-                        .map(|ann| {
-                            if ann.annotation.is_satisfied {
-                                match ann.annotation.kind.kind() {
-                                    "threshold" => score += 3.33333/10.0,
-                                    "source" => score += 1.33333/10.0,
-                                    "tls" => score += 2.00000/10.0,
-                                    "pki" => score += 3.333333/10.0,
-                                    _ => ()
-                                }
-                            }
-                            ann.annotation.clone()
-                        })
-                        .collect::<Vec<Annotation>>();
+            // Only the most recent readings are shown, the totals come from the sensor statistics
+            let readings = records.readings(&id)
+                .take(75)
+                .map(|entry| {
+                    let linked = records.annotations_of(entry);
+                    let annotations = linked.iter()
+                        .map(|ann| AnnotationDashboardContext { annotation: ann.annotation.clone(), verified: ann.verified })
+                        .collect::<Vec<AnnotationDashboardContext>>();
                     debug!("Annotations for {}: {}", id, annotations.len());
+                    let reading = &entry.reading;
+                    let signer = SensorKeyRegistry::key_at(registries, &reading.reading.id, reading.reading.timestamp);
                     let mut id = reading.id.clone();
                     id.truncate(10);
 
-
-
-                    readings.push(ReadingDashboardContext {
+                    ReadingDashboardContext {
                         id,
+                        full_id: reading.id.clone(),
                         timestamp: reading.reading.timestamp,
                         value: reading.reading.value,
                         address: reading.address.clone(),
                         annotations,
-                        score
-                    })
-                });
+                        signer,
+                        score: entry.score.value,
+                        explanation: entry.score.clone(),
+                    }
+                })
+                .collect::<Vec<ReadingDashboardContext>>();
             info!("Readings: {}", readings.len());
-            readings.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-            let mut avgcf = 0_f32;
-            let total = readings.iter().map(|r| avgcf += r.score ).count();
-            let avgcf = ((avgcf/total as f32) * 1000.0).round() / 1000.0;
+
+            let stats = records.stats(&id);
+            let retained = records.retained(&id);
+            let avgcf = if retained > 0 {
+                ((stats.score_sum/retained as f32) * 1000.0).round() / 1000.0
+            } else {
+                0.0
+            };
             debug!("Avgcf: {}", avgcf);
-            debug!("Total: {}", total);
+            debug!("Total: {}", stats.total);
+
+            // The heartbeat knows about readings that may not have reached the subscriber yet
+            let last_reading = stats.last_reading.max(
+                heartbeat.as_ref()
+                    .and_then(|hb| hb.sensors.iter().find(|s| s.id.eq(&id)))
+                    .map(|s| s.last_reading)
+            );
+            let stale = last_reading
+                .map(|last| (now - last).num_seconds() > STALE_SENSOR_SECS)
+                .unwrap_or(true);
 
-            readings.truncate(75);
             sensors.push(SensorDashboardContext {
                 id,
                 readings,
-                total,
+                total: stats.total,
                 avgcf: format!("{}", avgcf * 100.0),
+                last_reading,
+                stale,
             })
         }
 
         sensors.sort_by(|a, b| a.id.cmp(&b.id));
 
+        let publisher = match heartbeat {
+            Some(hb) => PublisherDashboardContext {
+                alive: (now - hb.timestamp).num_seconds() <= 2 * hb.interval_secs as i64,
+                last_heartbeat: Some(hb.timestamp),
+                uptime_secs: hb.uptime_secs,
+                sensors_active: hb.sensors_active,
+                outbox_depth: hb.outbox_depth,
+            },
+            None => PublisherDashboardContext {
+                alive: false,
+                last_heartbeat: None,
+                uptime_secs: 0,
+                sensors_active: 0,
+                outbox_depth: 0,
+            },
+        };
+
+        let mut orphan_readings = records.orphan_readings().into_iter()
+            .map(|reading| OrphanReadingDashboardContext {
+                id: reading.id.clone(),
+                sensor: reading.reading.id.clone(),
+                address: reading.address.clone(),
+                timestamp: reading.reading.timestamp,
+            })
+            .collect::<Vec<OrphanReadingDashboardContext>>();
+        orphan_readings.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        let mut orphan_annotations = records.orphan_annotations().into_iter()
+            .map(|ann| OrphanAnnotationDashboardContext {
+                key: ann.annotation.key.clone(),
+                kind: ann.annotation.kind.0.clone(),
+                hash: ann.annotation.hash.0.clone(),
+                reading_address: ann.reading_address.clone(),
+                timestamp: ann.annotation.timestamp.clone(),
+            })
+            .collect::<Vec<OrphanAnnotationDashboardContext>>();
+        orphan_annotations.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        let orphans = OrphansDashboardContext {
+            reading_count: orphan_readings.len(),
+            annotation_count: orphan_annotations.len(),
+            readings: orphan_readings.into_iter().take(20).collect(),
+            annotations: orphan_annotations.into_iter().take(20).collect(),
+        };
+
         DashboardContext {
-            sensors
+            publisher,
+            sensors,
+            orphans,
         }
     }
 }
@@ -145,119 +427,186 @@ impl DashboardContext {
 
 #[get("/")]
 async fn index(state: &State<AppState>) -> Template {
-    let messages = state.messages.lock().await.clone();
-    let annotations = state.annotations.lock().await.clone();
+    let sensors = state.stream.sensors.iter().map(|s| s.id.clone()).collect();
+    let heartbeat = state.heartbeat.lock().await.clone();
+    let registries = state.store.sensor_keys().unwrap_or_else(|e| {
+        error!("Failed to read the sensor key registries: {}", e);
+        Vec::new()
+    });
+    let records = state.records.lock().await;
 
-    info!("Messages: {}, Annotations: {}", messages.len(), annotations.len());
+    info!("Messages: {}, Annotations: {}", records.reading_count(), records.annotation_count());
 
-    Template::render("index", DashboardContext::new(messages, annotations))
+    Template::render("index", DashboardContext::new(&records, sensors, heartbeat, &registries))
 }
 
+/// Score of a reading along with the contribution of each of its annotations and the required
+/// annotations it is missing
+#[get("/scores/<id>")]
+async fn reading_score(id: &str, state: &State<AppState>) -> Option<Json<Score>> {
+    let records = state.records.lock().await;
+    Some(Json(records.reading(id)?.score.clone()))
+}
 
-#[rocket::launch]
-async fn rocket() -> _ {
-    logger::init().unwrap();
+/// Scores a reading had under every version of the scoring policy
+#[get("/scores/<id>/history")]
+async fn reading_score_history(id: &str, state: &State<AppState>) -> Option<Json<Vec<ScoreRecord>>> {
+    let scores = state.store.scores_for_reading(id)
+        .map_err(|e| error!("Failed to read scores of reading {}: {}", id, e))
+        .ok()?;
+    (!scores.is_empty()).then(|| Json(scores))
+}
 
-    let psk = Psk::from_seed("A pre shared key seed");
-    let announcement = fetch_user_announcement().await;
-    let mut readings: Vec<ReadingWrap> = Vec::new();
-    let mut annotations: Vec<AnnotationWrap> = Vec::new();
+/// Scores of the readings of a sensor under each version of the scoring policy, for comparing them
+#[get("/sensors/<id>/scores?<limit>")]
+async fn sensor_scores(id: &str, limit: Option<usize>, state: &State<AppState>) -> Option<Json<Vec<PolicyComparison>>> {
+    let current = state.records.lock().await.version().to_string();
+    let policies = state.store.policies()
+        .map_err(|e| error!("Failed to read scoring policies: {}", e))
+        .ok()?;
+    let mut comparisons = Vec::new();
+    for policy in policies {
+        let scores = state.store.scores_by_sensor(id, &policy.version)
+            .map_err(|e| error!("Failed to read scores of sensor {}: {}", id, e))
+            .ok()?;
+        comparisons.push(PolicyComparison::new(policy, &current, scores, limit.unwrap_or(75)));
+    }
+    Some(Json(comparisons))
+}
 
-    let user = match std::fs::read("user.bin") {
-        Err(_) => {
-            let mut user = streams::User::builder()
-                .with_identity(Ed25519::from_seed("Subscriber Seed"))
-                .with_transport(Client::new(NODE_URL))
-                .with_psk(psk.to_pskid(), psk)
-                .build();
-            user.receive_message(announcement).await.unwrap();
-            let backup = user.backup("password").await.unwrap();
-            std::fs::write("user.bin", backup).unwrap();
-            user
-        },
-        Ok(bytes) => {
-            let user = User::restore(bytes, "password", Client::new(NODE_URL)).await.unwrap();
+/// Every sensor key registry published so far, oldest first, for auditing which key vouched for a
+/// reading at the time it was annotated
+#[get("/sensor_keys")]
+async fn sensor_keys(state: &State<AppState>) -> Option<Json<Vec<SensorKeyRegistry>>> {
+    state.store.sensor_keys()
+        .map_err(|e| error!("Failed to read the sensor key registries: {}", e))
+        .ok()
+        .map(Json)
+}
 
-            let reading_bytes = std::fs::read("readings.bin").unwrap();
-            readings = serde_json::from_slice(&reading_bytes).unwrap();
-            let annotation_bytes = std::fs::read("annotations.bin").unwrap();
-            annotations = serde_json::from_slice(&annotation_bytes).unwrap();
 
-            user
-        }
-    };
+/// Serves the dashboard, or with `resync` rebuilds the store from the stream and exits
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
+    logger::init().unwrap();
 
-    info!("User received announcement");
+    match std::env::args().nth(1).as_deref() {
+        Some("resync") => resync::run().await,
+        Some(command) => error!("Unknown command {}, the only command is resync", command),
+        None => {
+            rocket().await.launch().await?;
+        },
+    }
+    Ok(())
+}
+
+async fn rocket() -> Rocket<Build> {
+    let psk = Psk::from_seed("A pre shared key seed");
+    let stream = fetch_stream_metadata().await;
+    // A key pinned in the configuration takes precedence over the one advertised by the publisher
+    let config = SubscriberConfig::load();
+    let verifier = Arc::new(AnnotationVerifier::new(
+        config.publisher_public_key.as_deref().unwrap_or(&stream.public_key)
+    ));
+    let announcement = Address::from_str(&stream.announcement_id).unwrap();
+    info!("Discovered sensors: {:?}", stream.sensors.iter().map(|s| s.id.as_str()).collect::<Vec<&str>>());
+    let records = Records::new(config.retention.clone(), ScoringPolicy::load());
+    let state::State { user, store, records } = state::restore(announcement, psk, &stream.topic_names(), &verifier, records).await;
 
     rocket::build()
         .attach(Template::fairing())
         .attach(MessageFetcher)
         .manage(AppState {
-            messages: Arc::new(Mutex::new(readings)),
-            annotations: Arc::new(Mutex::new(annotations)),
-            user: Arc::new(Mutex::new(user))
+            records: Arc::new(Mutex::new(records)),
+            user: Arc::new(Mutex::new(user)),
+            stream,
+            heartbeat: Arc::new(Mutex::new(None)),
+            verifier,
+            store,
         })
-        .mount("/", routes![index])
+        .mount("/", routes![index, reading_score, reading_score_history, sensor_scores, sensor_keys])
         .mount("/static", rocket::fs::FileServer::from("./static"))
 }
 
 struct AppState {
-    messages: Arc<Mutex<Vec<ReadingWrap>>>,
-    annotations: Arc<Mutex<Vec<AnnotationWrap>>>,
-    user: Arc<Mutex<User<Client>>>
+    /// Readings and annotations within the retention policy, along with their scores
+    records: Arc<Mutex<Records>>,
+    user: Arc<Mutex<User<Client>>>,
+    stream: StreamMetadata,
+    /// Latest heartbeat received from the publisher
+    heartbeat: Arc<Mutex<Option<Heartbeat>>>,
+    /// Checks annotations were signed by the publisher
+    verifier: Arc<AnnotationVerifier>,
+    /// Persisted readings and annotations
+    store: Store,
 }
 
 struct MessageFetcher;
 
 async fn unpack_message(
-    messages: Arc<Mutex<Vec<ReadingWrap>>>,
-    annotations: Arc<Mutex<Vec<AnnotationWrap>>>,
+    registry: &HandlerRegistry,
+    records: Arc<Mutex<Records>>,
+    store: &Store,
+    verifier: &AnnotationVerifier,
     msg: Message
 ) {
-    let address = msg.address.to_blake2b();
+    let address = hex::encode(msg.address.to_blake2b());
     if let streams::MessageContent::SignedPacket(msg) = msg.content {
-        match serde_json::from_slice::<SensorReading>(&msg.masked_payload) {
-            Ok(reading) => {
-                let id = Sha256Provider::new().derive(&msg.masked_payload);
-                info!("Found reading: {}", id);
-                let reading = ReadingWrap { id, reading, address: hex::encode(address)  };
-
-                messages.lock().await.push(reading)
-            },
-            Err(_) => {
-                match serde_json::from_slice::<MessageWrapper>(&msg.masked_payload) {
-                    Ok(annotation) => {
-                        let content = base64::engine::general_purpose::STANDARD.decode(annotation.content).unwrap();
-                        unpack_annotations(annotations, content).await
-                    },
-                    Err(_) => {
-                        error!("Not a known message type")
-                    },
+        match codec::decode_frame(&msg.masked_payload) {
+            Ok(Some(frame)) => {
+                if let Err(e) = registry.dispatch(&frame, &address).await {
+                    error!("Failed to handle message {}: {}", address, e)
                 }
-            }
+            },
+            Ok(None) => unpack_legacy(records, store, verifier, &msg.masked_payload, address).await,
+            Err(e) => error!("Failed to decode payload: {}", e),
         }
     }
 }
 
-async fn unpack_annotations(annotations: Arc<Mutex<Vec<AnnotationWrap>>>, content: Vec<u8>) {
-    match serde_json::from_slice::<AnnotationList>(&content) {
-        Ok(annotation_list) => {
-            let mut anns = String::new();
-            anns.push_str(&format!("Found annotations for {}: ", annotation_list.items[0].key));
-            for annotation in annotation_list.items {
-                anns.push_str(&format!("{}  ", annotation.kind.0));
-                let reading_id = annotation.key.clone();
-                let annotation = AnnotationWrap { reading_id, annotation };
-
-                annotations.lock().await.push(annotation)
+/// Payloads of publishers that predate the envelope are plain json, and their type has to be guessed
+async fn unpack_legacy(
+    records: Arc<Mutex<Records>>,
+    store: &Store,
+    verifier: &AnnotationVerifier,
+    payload: &[u8],
+    address: String,
+) {
+    match serde_json::from_slice::<SensorReading>(payload) {
+        Ok(reading) => {
+            let id = Sha256Provider::new().derive(payload);
+            info!("Found reading: {}", id);
+            let payload = String::from_utf8(payload.to_vec()).ok();
+            let reading = ReadingWrap { id, reading, address, batched: false, payload };
+            if let Err(e) = store.insert_reading(&reading) {
+                error!("Failed to store reading {}: {}", reading.id, e);
             }
-            info!("{}", anns);
+
+            records.lock().await.push_reading(reading)
         },
-        Err(_) => error!("failed to parse annotation list")
+        Err(_) => {
+            match serde_json::from_slice::<MessageWrapper>(payload) {
+                Ok(annotation) => {
+                    let content = match base64::engine::general_purpose::STANDARD.decode(annotation.content) {
+                        Ok(content) => content,
+                        Err(e) => {
+                            error!("Failed to decode annotation list in {}: {}", address, e);
+                            return
+                        },
+                    };
+                    match serde_json::from_slice::<AnnotationList>(&content) {
+                        Ok(annotation_list) => handlers::unpack_annotations(records, store, verifier, annotation_list.items, None).await,
+                        Err(_) => error!("failed to parse annotation list")
+                    }
+                },
+                Err(_) => {
+                    error!("Not a known message type")
+                },
+            }
+        }
     }
 }
 
-
 #[rocket::async_trait]
 impl Fairing for MessageFetcher {
     fn info(&self) -> Info {
@@ -269,29 +618,36 @@ impl Fairing for MessageFetcher {
 
     async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
         let state = rocket.state::<AppState>().unwrap();
-        let messages = state.messages.clone();
-        let annotations = state.annotations.clone();
+        let records = state.records.clone();
         let user = state.user.clone();
+        let heartbeat = state.heartbeat.clone();
+        let verifier = state.verifier.clone();
+        let store = state.store.clone();
 
+        // Scores follow the scoring policy, history included
+        rescoring::spawn(records.clone(), store.clone(), verifier.clone());
         tokio::spawn(async move {
+            let registry = HandlerRegistry::with_defaults(
+                records.clone(),
+                heartbeat,
+                verifier.clone(),
+                store.clone(),
+            );
             loop {
                 let mut user = user.lock().await;
                 if let Some(msg) = user.messages().next().await {
                     if let Ok(msg) = msg {
-                        unpack_message(messages.clone(), annotations.clone(), msg).await
+                        unpack_message(&registry, records.clone(), &store, &verifier, msg).await
                     }
                 } else {
                     tokio::time::sleep(Duration::from_secs(1)).await
                 };
+                records.lock().await.prune(chrono::Utc::now());
 
+                // Readings and annotations are stored by their handlers as they arrive, only the
+                // stream cursor is backed up here
                 let backup = user.backup("password").await.unwrap();
-                std::fs::write("user.bin", backup).unwrap();
-
-                let readings = messages.lock().await.clone().into_iter().collect::<Vec<ReadingWrap>>();
-                std::fs::write("readings.bin",serde_json::to_vec(&readings).unwrap()).unwrap();
-                let annotations = annotations.lock().await.clone().into_iter().collect::<Vec<AnnotationWrap>>();
-                std::fs::write("annotations.bin",serde_json::to_vec(&annotations).unwrap()).unwrap();
-
+                std::fs::write(state::USER_PATH, backup).unwrap();
             }
         });
 
@@ -300,8 +656,41 @@ impl Fairing for MessageFetcher {
 }
 
 
-async fn fetch_user_announcement() -> Address {
-    let response = reqwest::get("http://localhost:8900/get_announcement_id")
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SensorDescription {
+    id: String,
+    topic: String,
+    description: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TopicInfo {
+    name: String,
+    created: Option<chrono::DateTime<chrono::Utc>>,
+    last_message: Option<String>,
+}
+
+/// Stream metadata as returned by the publisher `/stream` endpoint
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    announcement_id: String,
+    author: String,
+    public_key: String,
+    topics: Vec<TopicInfo>,
+    annotation_topic: String,
+    annotators: Vec<String>,
+    sensors: Vec<SensorDescription>,
+}
+
+impl StreamMetadata {
+    /// Names of the branches of the stream, which can be subscribed to
+    fn topic_names(&self) -> Vec<String> {
+        self.topics.iter().map(|topic| topic.name.clone()).collect()
+    }
+}
+
+async fn fetch_stream_metadata() -> StreamMetadata {
+    let response = reqwest::get(format!("{}/stream", PROVIDER_URL))
         .await
         .map_err(|_| "Failed to query the provider".to_string())
         .unwrap()
@@ -310,11 +699,5 @@ async fn fetch_user_announcement() -> Address {
         .map_err(|_| "Failed to get response text".to_string())
         .unwrap();
 
-    #[derive(serde::Serialize, serde::Deserialize)]
-    struct AnnouncementResponse {
-        announcement_id: String
-    }
-
-    let address = serde_json::from_str::<AnnouncementResponse>(&response).unwrap();
-    Address::from_str(&address.announcement_id).unwrap()
-}
\ No newline at end of file
+    serde_json::from_str::<StreamMetadata>(&response).unwrap()
+}
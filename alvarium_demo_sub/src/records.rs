@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use alvarium_annotator::constants::HashType;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::scoring::{new_engine, Score, ScoreRecord, ScoringEngine, ScoringPolicy};
+use crate::store::Store;
+use crate::{AnnotationWrap, ReadingWrap};
+
+/// Bounds of the readings kept in memory for the dashboard, the store keeps every reading
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicy {
+    /// Readings older than this are dropped, along with the annotations of no remaining reading
+    pub max_age_secs: Option<u64>,
+    /// Only the most recent readings of each sensor are kept
+    pub max_readings_per_sensor: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Keep every reading, for going through the whole history
+    pub fn unbounded() -> Self {
+        RetentionPolicy { max_age_secs: None, max_readings_per_sensor: None }
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age_secs: Some(7 * 24 * 60 * 60),
+            max_readings_per_sensor: Some(1000),
+        }
+    }
+}
+
+/// A retained reading, along with its key under each hash type annotations were seen with and its
+/// current score
+pub struct Entry {
+    pub reading: ReadingWrap,
+    /// Hash type name -> hash of the reading payload
+    keys: HashMap<String, String>,
+    pub score: Score,
+}
+
+/// Statistics of a sensor, updated as readings and annotations arrive rather than on every page load
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SensorStats {
+    /// Readings received for the sensor, whether they are still retained or not
+    pub total: usize,
+    /// Sum of the scores of the retained readings
+    pub score_sum: f32,
+    pub last_reading: Option<DateTime<Utc>>,
+}
+
+/// Readings and annotations kept in memory, bounded by the retention policy. Annotations are indexed
+/// by their key, the hash of the reading they annotate, so that the annotations of a reading are
+/// looked up rather than searched for. Readings are indexed by id, and the orphans on either side are
+/// tracked as records come and go.
+pub struct Records {
+    retention: RetentionPolicy,
+    engine: Box<dyn ScoringEngine>,
+    /// Version of the scoring policy the engine applies
+    version: String,
+    /// Store scores are persisted to as they are computed, if any
+    store: Option<Store>,
+    /// Reading id -> retained reading
+    entries: HashMap<String, Entry>,
+    /// Sensor id -> ids of the retained readings, oldest first
+    readings: HashMap<String, VecDeque<String>>,
+    /// Annotation key -> annotations
+    annotations: HashMap<String, Vec<AnnotationWrap>>,
+    /// Reading key -> ids of the retained readings with that key
+    owners: HashMap<String, Vec<String>>,
+    /// Hash type name -> hash type, for every hash type annotations were seen with
+    hash_types: HashMap<String, HashType>,
+    /// Sensor id -> statistics
+    stats: HashMap<String, SensorStats>,
+    /// Ids of the retained readings no annotation belongs to
+    orphan_readings: HashSet<String>,
+    /// Annotation id -> key, for the retained annotations that belong to no retained reading
+    orphan_annotations: HashMap<String, String>,
+}
+
+impl Records {
+    pub fn new(retention: RetentionPolicy, policy: ScoringPolicy) -> Self {
+        Records {
+            retention,
+            version: policy.version(),
+            engine: new_engine(policy),
+            store: None,
+            entries: HashMap::new(),
+            readings: HashMap::new(),
+            annotations: HashMap::new(),
+            owners: HashMap::new(),
+            hash_types: HashMap::new(),
+            stats: HashMap::new(),
+            orphan_readings: HashSet::new(),
+            orphan_annotations: HashMap::new(),
+        }
+    }
+
+    /// Persist scores to the store under the policy version as they are computed
+    pub fn persisting(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Version of the scoring policy readings are scored under
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Score every retained reading again under another scoring policy
+    pub fn set_policy(&mut self, policy: ScoringPolicy) {
+        self.version = policy.version();
+        self.engine = new_engine(policy);
+        let scores = self.entries.iter()
+            .map(|(id, entry)| (id.clone(), self.score(&entry.reading, &entry.keys)))
+            .collect::<Vec<(String, Score)>>();
+        for (id, score) in scores {
+            if let Some(entry) = self.entries.get_mut(&id) {
+                entry.score = score;
+                persist(&self.store, &self.version, entry);
+            }
+        }
+        for (sensor, ids) in &self.readings {
+            let stats = self.stats.entry(sensor.clone()).or_default();
+            stats.score_sum = ids.iter().filter_map(|id| self.entries.get(id)).map(|entry| entry.score.value).sum();
+        }
+    }
+
+    /// Add a reading, unless it is already retained as happens when the stream is replayed
+    pub fn push_reading(&mut self, reading: ReadingWrap) {
+        if self.entries.contains_key(&reading.id) {
+            return
+        }
+        let sensor = reading.reading.id.clone();
+        let id = reading.id.clone();
+        let timestamp = reading.reading.timestamp;
+        // Readings are only hashed with the hash types annotations use
+        let keys = self.hash_types.iter()
+            .filter_map(|(name, hash)| reading.key(hash).map(|key| (name.clone(), key)))
+            .collect::<HashMap<String, String>>();
+        let score = self.score(&reading, &keys);
+        for key in keys.values() {
+            self.owners.entry(key.clone()).or_default().push(id.clone());
+        }
+
+        let stats = self.stats.entry(sensor.clone()).or_default();
+        stats.total += 1;
+        stats.score_sum += score.value;
+        stats.last_reading = stats.last_reading.max(Some(timestamp));
+
+        let entry = Entry { reading, keys, score };
+        let linked = self.linked(&entry.reading, &entry.keys).into_iter()
+            .map(|ann| ann.annotation.id.clone())
+            .collect::<Vec<String>>();
+        if linked.is_empty() {
+            self.orphan_readings.insert(id.clone());
+        }
+        for annotation in linked {
+            self.orphan_annotations.remove(&annotation);
+        }
+        persist(&self.store, &self.version, &entry);
+
+        // Readings mostly arrive in order, so the position is searched from the newest one
+        let entries = &self.entries;
+        let readings = self.readings.entry(sensor.clone()).or_default();
+        let position = readings.iter()
+            .rposition(|other| entries.get(other).map(|other| other.reading.reading.timestamp <= timestamp).unwrap_or(true))
+            .map(|position| position + 1)
+            .unwrap_or(0);
+        readings.insert(position, id.clone());
+        self.entries.insert(id, entry);
+        self.enforce(&sensor, None);
+    }
+
+    /// Add an annotation, unless it is already retained as happens when the stream is replayed
+    pub fn push_annotation(&mut self, annotation: AnnotationWrap) {
+        let known = self.annotations.get(&annotation.annotation.key)
+            .map(|annotations| annotations.iter().any(|ann| ann.annotation.id.eq(&annotation.annotation.id)))
+            .unwrap_or(false);
+        if known {
+            return
+        }
+        let hash = annotation.annotation.hash.clone();
+        if !self.hash_types.contains_key(&hash.0) {
+            for entry in self.entries.values_mut() {
+                if let Some(key) = entry.reading.key(&hash) {
+                    self.owners.entry(key.clone()).or_default().push(entry.reading.id.clone());
+                    entry.keys.insert(hash.0.clone(), key);
+                }
+            }
+            self.hash_types.insert(hash.0.clone(), hash);
+        }
+
+        let key = annotation.annotation.key.clone();
+        let owners = self.owners_of(&annotation);
+        if owners.is_empty() {
+            self.orphan_annotations.insert(annotation.annotation.id.clone(), key.clone());
+        }
+        for owner in owners {
+            self.orphan_readings.remove(&owner);
+        }
+        self.annotations.entry(key.clone()).or_default().push(annotation);
+        self.rescore(&key);
+    }
+
+    /// Drop the readings that fall out of the retention policy, and the annotations that are too old
+    /// and belong to no retained reading
+    pub fn prune(&mut self, now: DateTime<Utc>) {
+        let cutoff = self.retention.max_age_secs.map(|secs| now - chrono::Duration::seconds(secs as i64));
+        let sensors = self.readings.keys().cloned().collect::<Vec<String>>();
+        for sensor in sensors {
+            self.enforce(&sensor, cutoff);
+        }
+
+        if let Some(cutoff) = cutoff {
+            let owners = &self.owners;
+            let orphans = &mut self.orphan_annotations;
+            self.annotations.retain(|key, annotations| {
+                if owners.contains_key(key) {
+                    return true
+                }
+                annotations.retain(|ann| {
+                    let recent = DateTime::parse_from_rfc3339(&ann.annotation.timestamp)
+                        .map(|timestamp| timestamp.with_timezone(&Utc) >= cutoff)
+                        .unwrap_or(true);
+                    if !recent {
+                        orphans.remove(&ann.annotation.id);
+                    }
+                    recent
+                });
+                !annotations.is_empty()
+            });
+        }
+    }
+
+    /// Drop the oldest readings of a sensor while it has too many or they are older than the cutoff
+    fn enforce(&mut self, sensor: &str, cutoff: Option<DateTime<Utc>>) {
+        while let Some(oldest) = self.readings.get(sensor)
+            .and_then(|readings| readings.front())
+            .and_then(|id| self.entries.get(id))
+        {
+            let expired = cutoff.map(|cutoff| oldest.reading.reading.timestamp < cutoff).unwrap_or(false);
+            let excess = self.retention.max_readings_per_sensor
+                .map(|max| self.retained(sensor) > max)
+                .unwrap_or(false);
+            if !expired && !excess {
+                break
+            }
+            self.pop_oldest(sensor);
+        }
+    }
+
+    /// Drop the oldest reading of a sensor, along with the annotations of no other retained reading
+    fn pop_oldest(&mut self, sensor: &str) -> Option<Entry> {
+        let id = self.readings.get_mut(sensor)?.pop_front()?;
+        let entry = self.entries.remove(&id)?;
+        if let Some(stats) = self.stats.get_mut(sensor) {
+            stats.score_sum -= entry.score.value;
+        }
+        self.orphan_readings.remove(&id);
+
+        let linked = self.linked(&entry.reading, &entry.keys).into_iter()
+            .map(|ann| (ann.annotation.id.clone(), ann.annotation.key.clone()))
+            .collect::<Vec<(String, String)>>();
+        for key in entry.keys.values() {
+            if let Some(owners) = self.owners.get_mut(key) {
+                owners.retain(|owner| !owner.eq(&id));
+                if owners.is_empty() {
+                    // The annotations of a dropped reading go with it
+                    self.owners.remove(key);
+                    for ann in self.annotations.remove(key).into_iter().flatten() {
+                        self.orphan_annotations.remove(&ann.annotation.id);
+                    }
+                }
+            }
+        }
+        // Annotations that were shared with other readings may have lost the last one they belong to
+        for (annotation, key) in linked {
+            let orphaned = self.annotations.get(&key)
+                .and_then(|annotations| annotations.iter().find(|ann| ann.annotation.id.eq(&annotation)))
+                .map(|ann| self.owners_of(ann).is_empty())
+                .unwrap_or(false);
+            if orphaned {
+                self.orphan_annotations.insert(annotation, key);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Score the readings annotations with the given key belong to again
+    fn rescore(&mut self, key: &str) {
+        let owners = self.owners.get(key).cloned().unwrap_or_default();
+        for id in owners {
+            let score = match self.entries.get(&id) {
+                Some(entry) => self.score(&entry.reading, &entry.keys),
+                None => continue,
+            };
+            if let Some(entry) = self.entries.get_mut(&id) {
+                let stats = self.stats.entry(entry.reading.reading.id.clone()).or_default();
+                stats.score_sum += score.value - entry.score.value;
+                entry.score = score;
+                persist(&self.store, &self.version, entry);
+            }
+        }
+    }
+
+    /// Score of a reading from its verified annotations, annotations that could have been forged do
+    /// not count towards it
+    fn score(&self, reading: &ReadingWrap, keys: &HashMap<String, String>) -> Score {
+        let trusted = self.linked(reading, keys).into_iter()
+            .filter(|ann| ann.verified)
+            .collect::<Vec<&AnnotationWrap>>();
+        self.engine.score(reading, &trusted)
+    }
+
+    /// Annotations of a reading: those whose key is the hash of the reading payload under the hash type
+    /// the annotation declares and, if the publisher linked them, that were published for the message
+    /// the reading was sent in
+    fn linked<'a>(&'a self, reading: &ReadingWrap, keys: &HashMap<String, String>) -> Vec<&'a AnnotationWrap> {
+        keys.iter()
+            .flat_map(|(hash, key)| {
+                self.annotations.get(key).into_iter().flatten().filter(move |ann| {
+                    ann.annotation.hash.0.eq(hash)
+                        && ann.reading_address.as_ref().map(|a| a.eq(&reading.address)).unwrap_or(true)
+                })
+            })
+            .collect()
+    }
+
+    /// Ids of the retained readings an annotation belongs to
+    fn owners_of(&self, annotation: &AnnotationWrap) -> Vec<String> {
+        self.owners.get(&annotation.annotation.key).into_iter().flatten()
+            .filter(|id| self.entries.get(*id).map(|entry| belongs(annotation, entry)).unwrap_or(false))
+            .cloned()
+            .collect()
+    }
+
+    /// Retained reading with the given id, whatever its sensor
+    pub fn reading(&self, id: &str) -> Option<&Entry> {
+        self.entries.get(id)
+    }
+
+    /// Annotations of a retained reading, verified or not
+    pub fn annotations_of(&self, entry: &Entry) -> Vec<&AnnotationWrap> {
+        self.linked(&entry.reading, &entry.keys)
+    }
+
+    pub fn sensors(&self) -> Vec<String> {
+        self.readings.keys().cloned().collect()
+    }
+
+    /// Retained readings of a sensor, newest first
+    pub fn readings(&self, sensor: &str) -> impl Iterator<Item = &Entry> {
+        self.readings.get(sensor).into_iter()
+            .flat_map(move |ids| ids.iter().rev().filter_map(move |id| self.entries.get(id)))
+    }
+
+    pub fn retained(&self, sensor: &str) -> usize {
+        self.readings.get(sensor).map(|readings| readings.len()).unwrap_or_default()
+    }
+
+    pub fn stats(&self, sensor: &str) -> SensorStats {
+        self.stats.get(sensor).cloned().unwrap_or_default()
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn annotation_count(&self) -> usize {
+        self.annotations.values().map(|annotations| annotations.len()).sum()
+    }
+
+    /// Retained readings whose hash matches no annotation key
+    pub fn orphan_readings(&self) -> Vec<&ReadingWrap> {
+        self.orphan_readings.iter()
+            .filter_map(|id| self.entries.get(id))
+            .map(|entry| &entry.reading)
+            .collect()
+    }
+
+    /// Annotations whose key matches no retained reading
+    pub fn orphan_annotations(&self) -> Vec<&AnnotationWrap> {
+        self.orphan_annotations.iter()
+            .filter_map(|(id, key)| self.annotations.get(key)?.iter().find(|ann| ann.annotation.id.eq(id)))
+            .collect()
+    }
+}
+
+/// Whether an annotation belongs to a reading, the same way `Records::linked` matches them
+fn belongs(annotation: &AnnotationWrap, entry: &Entry) -> bool {
+    entry.keys.get(&annotation.annotation.hash.0).map(|key| key.eq(&annotation.annotation.key)).unwrap_or(false)
+        && annotation.reading_address.as_ref().map(|a| a.eq(&entry.reading.address)).unwrap_or(true)
+}
+
+/// Save the score of a reading under the policy version, if scores are persisted
+fn persist(store: &Option<Store>, version: &str, entry: &Entry) {
+    if let Some(store) = store {
+        let record = ScoreRecord {
+            reading_id: entry.reading.id.clone(),
+            sensor: entry.reading.reading.id.clone(),
+            timestamp: entry.reading.reading.timestamp,
+            version: version.to_string(),
+            score: entry.score.clone(),
+        };
+        if let Err(e) = store.insert_score(&record) {
+            error!("Failed to store score of reading {}: {}", record.reading_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alvarium_annotator::{Annotation, HashProvider};
+    use alvarium_annotator::constants::AnnotationType;
+    use alvarium_sdk_rust::providers::hash_provider::Sha256Provider;
+    use crate::SensorReading;
+    use super::*;
+
+    fn reading(sensor: &str, value: u8, age_secs: i64) -> ReadingWrap {
+        let reading = SensorReading {
+            id: sensor.to_string(),
+            value,
+            timestamp: Utc::now() - chrono::Duration::seconds(age_secs),
+        };
+        let payload = serde_json::to_string(&reading).unwrap();
+        ReadingWrap {
+            id: Sha256Provider::new().derive(payload.as_bytes()),
+            address: String::new(),
+            reading,
+            batched: false,
+            payload: Some(payload),
+        }
+    }
+
+    fn annotation(id: &str, key: &str) -> AnnotationWrap {
+        let mut annotation = Annotation::new(
+            key,
+            HashType("sha256".to_string()),
+            "host",
+            AnnotationType("pki".to_string()),
+            true,
+        );
+        annotation.id = id.to_string();
+        AnnotationWrap {
+            reading_id: key.to_string(),
+            reading_address: None,
+            verified: true,
+            annotation,
+        }
+    }
+
+    fn key(reading: &ReadingWrap) -> String {
+        Sha256Provider::new().derive(reading.payload.as_ref().unwrap().as_bytes())
+    }
+
+    fn records(max_readings_per_sensor: Option<usize>) -> Records {
+        let retention = RetentionPolicy { max_age_secs: None, max_readings_per_sensor };
+        Records::new(retention, ScoringPolicy::default())
+    }
+
+    #[test]
+    fn readings_are_looked_up_by_id_and_kept_in_order() {
+        let mut records = records(None);
+        let (old, new) = (reading("sensor", 1, 60), reading("sensor", 2, 0));
+        records.push_reading(new.clone());
+        records.push_reading(old.clone());
+        records.push_reading(new.clone());
+
+        assert_eq!(records.reading_count(), 2);
+        assert_eq!(records.reading(&old.id).unwrap().reading.reading.value, 1);
+        let values = records.readings("sensor").map(|entry| entry.reading.reading.value).collect::<Vec<u8>>();
+        assert_eq!(values, vec![2, 1]);
+    }
+
+    #[test]
+    fn orphans_are_tracked_as_records_arrive() {
+        let mut records = records(None);
+        let first = reading("sensor", 1, 10);
+        let second = reading("sensor", 2, 0);
+        records.push_annotation(annotation("a1", &key(&first)));
+        records.push_annotation(annotation("a2", "unknown"));
+        assert_eq!(records.orphan_annotations().len(), 2);
+
+        records.push_reading(first.clone());
+        records.push_reading(second.clone());
+        let orphans = records.orphan_annotations().iter().map(|ann| ann.annotation.id.clone()).collect::<Vec<String>>();
+        assert_eq!(orphans, vec!["a2".to_string()]);
+        let orphans = records.orphan_readings().iter().map(|reading| reading.id.clone()).collect::<Vec<String>>();
+        assert_eq!(orphans, vec![second.id.clone()]);
+
+        records.push_annotation(annotation("a3", &key(&second)));
+        assert!(records.orphan_readings().is_empty());
+        assert!(records.reading(&second.id).unwrap().score.value > 0.0);
+    }
+
+    #[test]
+    fn orphans_are_tracked_as_readings_are_dropped() {
+        let mut records = records(Some(1));
+        let first = reading("sensor", 1, 10);
+        let second = reading("sensor", 2, 0);
+        records.push_annotation(annotation("a1", &key(&first)));
+        records.push_reading(first.clone());
+        assert!(records.orphan_annotations().is_empty());
+
+        // The first reading goes out of retention along with its annotation
+        records.push_reading(second.clone());
+        assert!(records.reading(&first.id).is_none());
+        assert_eq!(records.annotation_count(), 0);
+        assert!(records.orphan_annotations().is_empty());
+        assert_eq!(records.orphan_readings().len(), 1);
+        assert_eq!(records.reading_count(), 1);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Arc;
+use std::time::SystemTime;
+use chrono::Utc;
+use rocket::tokio::{self, sync::Mutex, time::Duration};
+use crate::errors::Result;
+use crate::records::{Records, RetentionPolicy};
+use crate::scoring::{PolicyRecord, ScoringPolicy, SCORING_POLICY_PATH};
+use crate::store::Store;
+use crate::verification::AnnotationVerifier;
+
+/// Seconds between two checks of the scoring policy file for changes
+const POLICY_CHECK_SECS: u64 = 10;
+
+/// Keep the scores of the whole history up to date with the scoring policy. Every stored reading is
+/// scored under the policy in effect unless that was already done, then again whenever the policy file
+/// changes, the retained readings being rescored right away and the rest of the history in the
+/// background.
+pub fn spawn(records: Arc<Mutex<Records>>, store: Store, verifier: Arc<AnnotationVerifier>) {
+    tokio::spawn(async move {
+        let mut modified = policy_modified();
+        let mut policy = ScoringPolicy::load();
+        loop {
+            let version = policy.version();
+            {
+                let mut records = records.lock().await;
+                if records.version() != version {
+                    info!("Scoring policy changed from {} to {}", records.version(), version);
+                    records.set_policy(policy.clone());
+                }
+            }
+
+            let complete = store.policy(&version).ok().flatten().map(|record| record.complete).unwrap_or(false);
+            if !complete {
+                let (store, verifier, policy) = (store.clone(), verifier.clone(), policy.clone());
+                match tokio::task::spawn_blocking(move || rescore_history(&store, &verifier, policy)).await {
+                    Ok(Ok(scored)) => info!("Rescored {} readings under scoring policy {}", scored, version),
+                    Ok(Err(e)) => error!("Failed to rescore history under scoring policy {}: {}", version, e),
+                    Err(e) => error!("Rescoring under scoring policy {} stopped: {}", version, e),
+                }
+            }
+
+            // Wait for the policy to change into a different one
+            loop {
+                tokio::time::sleep(Duration::from_secs(POLICY_CHECK_SECS)).await;
+                let current = policy_modified();
+                if current == modified {
+                    continue
+                }
+                modified = current;
+                let reloaded = ScoringPolicy::load();
+                if reloaded.version() != version {
+                    policy = reloaded;
+                    break
+                }
+            }
+        }
+    });
+}
+
+/// Score every stored reading under a policy and persist the scores, one sensor at a time so that only
+/// the readings of one sensor and their annotations, looked up by key, are held in memory. Returns the
+/// number of readings scored.
+pub fn rescore_history(store: &Store, verifier: &AnnotationVerifier, policy: ScoringPolicy) -> Result<usize> {
+    let version = policy.version();
+    let created = store.policy(&version)?.map(|record| record.created).unwrap_or_else(Utc::now);
+    store.insert_policy(&PolicyRecord { version: version.clone(), policy: policy.clone(), created, complete: false })?;
+
+    let hash_types = store.hash_types()?;
+    let sensors = store.sensors()?;
+    let mut scored = 0;
+    for (i, sensor) in sensors.iter().enumerate() {
+        let mut records = Records::new(RetentionPolicy::unbounded(), policy.clone()).persisting(store.clone());
+        let readings = store.readings_by_sensor(sensor)?;
+        let mut keys = HashSet::new();
+        for reading in &readings {
+            for key in hash_types.iter().filter_map(|hash| reading.key(hash)) {
+                if !keys.insert(key.clone()) {
+                    continue
+                }
+                for mut annotation in store.annotations_by_key(&key)? {
+                    annotation.verified = verifier.verify(&annotation.annotation);
+                    records.push_annotation(annotation);
+                }
+            }
+        }
+
+        scored += readings.len();
+        readings.into_iter().for_each(|reading| records.push_reading(reading));
+        info!("Rescoring under policy {}: {}/{} sensors, {} readings", version, i + 1, sensors.len(), scored);
+    }
+
+    store.insert_policy(&PolicyRecord { version, policy, created, complete: true })?;
+    store.flush()?;
+    Ok(scored)
+}
+
+fn policy_modified() -> Option<SystemTime> {
+    fs::metadata(SCORING_POLICY_PATH).and_then(|meta| meta.modified()).ok()
+}
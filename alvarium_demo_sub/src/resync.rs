@@ -0,0 +1,102 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use rocket::tokio::sync::Mutex;
+use streams::Address;
+use streams::id::Psk;
+use crate::config::SubscriberConfig;
+use crate::handlers::HandlerRegistry;
+use crate::records::Records;
+use crate::rescoring::rescore_history;
+use crate::scoring::ScoringPolicy;
+use crate::state;
+use crate::store::{Store, STORE_PATH};
+use crate::verification::AnnotationVerifier;
+use crate::{fetch_stream_metadata, unpack_message};
+
+/// Messages between two progress reports
+const PROGRESS_INTERVAL: usize = 100;
+
+/// Rebuild the store from scratch by walking the stream from its announcement through every branch,
+/// handling each message as the running subscriber would, then score the rebuilt history under the
+/// current scoring policy. The subscriber must not be running, as it holds the store.
+pub async fn run() {
+    let psk = Psk::from_seed("A pre shared key seed");
+    let stream = fetch_stream_metadata().await;
+    let config = SubscriberConfig::load();
+    let verifier = Arc::new(AnnotationVerifier::new(
+        config.publisher_public_key.as_deref().unwrap_or(&stream.public_key)
+    ));
+    let announcement = Address::from_str(&stream.announcement_id).unwrap();
+
+    // A user subscribed from the announcement receives every message of the stream again. The store is
+    // only cleared once the announcement was received, and the backup then points at the announcement, so
+    // a resync that is cut short is picked up by the next start replaying the stream.
+    let mut user = match state::new_user(announcement, psk, &stream.topic_names()).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("Failed to receive the stream announcement, {} was left as it was: {}", STORE_PATH, e);
+            return
+        }
+    };
+
+    let store = Store::open(STORE_PATH).unwrap();
+    info!(
+        "Clearing {} readings and {} annotations from {}",
+        store.reading_count(),
+        store.annotation_count(),
+        STORE_PATH,
+    );
+    store.clear().unwrap();
+
+    let policy = ScoringPolicy::load();
+    let records = Arc::new(Mutex::new(Records::new(config.retention.clone(), policy.clone())));
+    let registry = HandlerRegistry::with_defaults(
+        records.clone(),
+        Arc::new(Mutex::new(None)),
+        verifier.clone(),
+        store.clone(),
+    );
+
+    let started = Instant::now();
+    let mut received = 0;
+    let mut failed = 0;
+    while let Some(msg) = user.messages().next().await {
+        match msg {
+            Ok(msg) => unpack_message(&registry, records.clone(), &store, &verifier, msg).await,
+            Err(e) => {
+                warn!("Failed to fetch message: {}", e);
+                failed += 1;
+            }
+        }
+        received += 1;
+        if received % PROGRESS_INTERVAL == 0 {
+            info!(
+                "Resync progress: {} messages in {}s, {} readings and {} annotations stored",
+                received,
+                started.elapsed().as_secs(),
+                store.reading_count(),
+                store.annotation_count(),
+            );
+        }
+    }
+
+    // The subscriber carries on from the end of the stream when it is started again
+    store.flush().unwrap();
+    let backup = user.backup("password").await.unwrap();
+    std::fs::write(state::USER_PATH, backup).unwrap();
+    info!(
+        "Resync complete: {} messages ({} failed) in {}s, {} readings and {} annotations stored",
+        received,
+        failed,
+        started.elapsed().as_secs(),
+        store.reading_count(),
+        store.annotation_count(),
+    );
+
+    let version = policy.version();
+    match rescore_history(&store, &verifier, policy) {
+        Ok(scored) => info!("Scored {} readings under scoring policy {}", scored, version),
+        Err(e) => error!("Failed to score the resynced readings: {}", e),
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::{HashMap, HashSet};
+use alvarium_annotator::HashProvider;
+use alvarium_sdk_rust::providers::hash_provider::Sha256Provider;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::{AnnotationWrap, ReadingWrap};
+
+/// Path of the scoring policy, read when the subscriber starts and again whenever it changes
+pub const SCORING_POLICY_PATH: &'static str = "config/scoring.json";
+
+/// How the annotations of a reading are turned into a confidence score
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScoringPolicy {
+    /// Scoring strategy applying the policy
+    #[serde(default)]
+    pub engine: EngineKind,
+    /// Annotation type -> weight, types without a weight do not count towards the score
+    pub weights: HashMap<String, f32>,
+    /// Annotation types a reading must carry, readings missing any of them score zero
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Sensor id -> overrides of the policy for the readings of that sensor
+    #[serde(default)]
+    pub sensors: HashMap<String, SensorPolicy>,
+    /// Prior confidence in a reading before any annotation is considered, used by the bayesian engine
+    #[serde(default = "default_prior")]
+    pub prior: f32,
+    /// Annotation types checked at each hop of the path of a reading, from the device onwards, used
+    /// by the graph engine
+    #[serde(default = "default_hops")]
+    pub hops: Vec<Vec<String>>,
+}
+
+fn default_prior() -> f32 {
+    0.5
+}
+
+fn default_hops() -> Vec<Vec<String>> {
+    vec![
+        vec!["pki".to_string(), "source".to_string()],
+        vec!["tls".to_string(), "threshold".to_string()],
+    ]
+}
+
+/// Available scoring strategies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EngineKind {
+    #[default]
+    Weighted,
+    Bayesian,
+    MinimumOfRequired,
+    Graph,
+}
+
+/// Overrides of the scoring policy for a single sensor
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SensorPolicy {
+    /// Weights replacing the default ones for the listed annotation types
+    #[serde(default)]
+    pub weights: HashMap<String, f32>,
+    /// Required annotation types replacing the default ones when set
+    #[serde(default)]
+    pub required: Option<Vec<String>>,
+}
+
+impl Default for ScoringPolicy {
+    fn default() -> Self {
+        ScoringPolicy {
+            weights: HashMap::from([
+                ("threshold".to_string(), 3.33333),
+                ("source".to_string(), 1.33333),
+                ("tls".to_string(), 2.0),
+                ("pki".to_string(), 3.333333),
+            ]),
+            engine: EngineKind::default(),
+            required: Vec::new(),
+            sensors: HashMap::new(),
+            prior: default_prior(),
+            hops: default_hops(),
+        }
+    }
+}
+
+impl ScoringPolicy {
+    /// Load the scoring policy, falling back to the default weights if it is missing or malformed
+    pub fn load() -> Self {
+        match std::fs::read(SCORING_POLICY_PATH) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Failed to parse scoring policy, using the default weights: {}", e);
+                ScoringPolicy::default()
+            }),
+            Err(_) => ScoringPolicy::default(),
+        }
+    }
+
+    /// Identifies the policy by its content, so that scores computed under different policies can be
+    /// told apart. Maps are sorted when serialised as json values, which makes the version stable.
+    pub fn version(&self) -> String {
+        let canonical = serde_json::to_value(self).map(|value| value.to_string()).unwrap_or_default();
+        let mut version = Sha256Provider::new().derive(canonical.as_bytes());
+        version.truncate(12);
+        version
+    }
+
+    /// Weights in effect for the readings of a sensor
+    pub fn weights(&self, sensor: &str) -> HashMap<String, f32> {
+        let mut weights = self.weights.clone();
+        if let Some(overrides) = self.sensors.get(sensor) {
+            weights.extend(overrides.weights.clone());
+        }
+        weights
+    }
+
+    /// Annotation types required for the readings of a sensor
+    pub fn required(&self, sensor: &str) -> Vec<String> {
+        self.sensors.get(sensor)
+            .and_then(|overrides| overrides.required.clone())
+            .unwrap_or_else(|| self.required.clone())
+    }
+
+    /// Required annotation types of a sensor that none of the annotations are of
+    fn missing(&self, sensor: &str, annotations: &[&AnnotationWrap]) -> Vec<String> {
+        let kinds = annotations.iter().map(|ann| ann.annotation.kind.kind()).collect::<HashSet<&str>>();
+        self.required(sensor).into_iter().filter(|kind| !kinds.contains(kind.as_str())).collect()
+    }
+}
+
+/// Contribution of one annotation to the score of a reading
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Contribution {
+    pub kind: String,
+    pub satisfied: bool,
+    pub weight: f32,
+    /// Share of the score the annotation accounts for
+    pub contribution: f32,
+}
+
+/// Score of a reading between 0 and 1, along with how it was reached
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Score {
+    pub engine: EngineKind,
+    pub value: f32,
+    pub contributions: Vec<Contribution>,
+    /// Required annotation types the reading was not annotated with
+    pub missing: Vec<String>,
+}
+
+/// Score a reading had under a version of the scoring policy
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScoreRecord {
+    pub reading_id: String,
+    pub sensor: String,
+    /// Timestamp of the reading
+    pub timestamp: DateTime<Utc>,
+    pub version: String,
+    pub score: Score,
+}
+
+/// A version of the scoring policy readings were scored under
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PolicyRecord {
+    pub version: String,
+    pub policy: ScoringPolicy,
+    /// When the policy was first put in effect
+    pub created: DateTime<Utc>,
+    /// Every stored reading was scored under the policy
+    pub complete: bool,
+}
+
+impl Score {
+    /// Score of a reading missing required annotations
+    fn rejected(engine: EngineKind, annotations: &[&AnnotationWrap], weights: &HashMap<String, f32>, missing: Vec<String>) -> Self {
+        let contributions = annotations.iter()
+            .map(|ann| Contribution {
+                kind: ann.annotation.kind.kind().to_string(),
+                satisfied: ann.annotation.is_satisfied,
+                weight: weights.get(ann.annotation.kind.kind()).copied().unwrap_or_default(),
+                contribution: 0.0,
+            })
+            .collect();
+        Score { engine, value: 0.0, contributions, missing }
+    }
+}
+
+/// Turns the annotations of a reading into a confidence score
+pub trait ScoringEngine: Send + Sync {
+    fn score(&self, reading: &ReadingWrap, annotations: &[&AnnotationWrap]) -> Score;
+}
+
+/// Create the scoring engine selected in the policy
+pub fn new_engine(policy: ScoringPolicy) -> Box<dyn ScoringEngine> {
+    match policy.engine {
+        EngineKind::Weighted => Box::new(WeightedEngine(policy)),
+        EngineKind::Bayesian => Box::new(BayesianEngine(policy)),
+        EngineKind::MinimumOfRequired => Box::new(MinimumOfRequiredEngine(policy)),
+        EngineKind::Graph => Box::new(GraphEngine(policy)),
+    }
+}
+
+/// Only the first annotation of each type counts, however many annotations of it a reading has
+fn first_of_each_kind<'a>(annotations: &[&'a AnnotationWrap]) -> Vec<&'a AnnotationWrap> {
+    let mut seen = HashSet::new();
+    annotations.iter().filter(|ann| seen.insert(ann.annotation.kind.kind())).copied().collect()
+}
+
+/// Weight of the satisfied annotation types over the total weight in effect for the sensor
+pub struct WeightedEngine(pub ScoringPolicy);
+
+impl ScoringEngine for WeightedEngine {
+    fn score(&self, reading: &ReadingWrap, annotations: &[&AnnotationWrap]) -> Score {
+        let sensor = &reading.reading.id;
+        let weights = self.0.weights(sensor);
+        let missing = self.0.missing(sensor, annotations);
+        if !missing.is_empty() {
+            return Score::rejected(EngineKind::Weighted, annotations, &weights, missing)
+        }
+
+        let total = weights.values().sum::<f32>();
+        let contributions = first_of_each_kind(annotations).into_iter()
+            .map(|ann| {
+                let weight = weights.get(ann.annotation.kind.kind()).copied().unwrap_or_default();
+                let contribution = if ann.annotation.is_satisfied && total > 0.0 { weight / total } else { 0.0 };
+                Contribution { kind: ann.annotation.kind.kind().to_string(), satisfied: ann.annotation.is_satisfied, weight, contribution }
+            })
+            .collect::<Vec<Contribution>>();
+        let value = contributions.iter().map(|c| c.contribution).sum();
+        Score { engine: EngineKind::Weighted, value, contributions, missing }
+    }
+}
+
+/// Starts from the prior confidence and updates it with each annotation as a piece of evidence whose
+/// likelihood ratio is `1 + weight` when satisfied and its inverse otherwise
+pub struct BayesianEngine(pub ScoringPolicy);
+
+impl ScoringEngine for BayesianEngine {
+    fn score(&self, reading: &ReadingWrap, annotations: &[&AnnotationWrap]) -> Score {
+        let sensor = &reading.reading.id;
+        let weights = self.0.weights(sensor);
+        let missing = self.0.missing(sensor, annotations);
+        if !missing.is_empty() {
+            return Score::rejected(EngineKind::Bayesian, annotations, &weights, missing)
+        }
+
+        let prior = self.0.prior.clamp(0.001, 0.999);
+        let mut odds = prior / (1.0 - prior);
+        let mut contributions = Vec::new();
+        for ann in first_of_each_kind(annotations) {
+            let weight = weights.get(ann.annotation.kind.kind()).copied().unwrap_or_default().max(0.0);
+            let before = odds / (1.0 + odds);
+            let ratio = 1.0 + weight;
+            odds = if ann.annotation.is_satisfied { odds * ratio } else { odds / ratio };
+            contributions.push(Contribution {
+                kind: ann.annotation.kind.kind().to_string(),
+                satisfied: ann.annotation.is_satisfied,
+                weight,
+                // Change in confidence brought by the annotation, negative for failed checks
+                contribution: odds / (1.0 + odds) - before,
+            });
+        }
+        Score { engine: EngineKind::Bayesian, value: odds / (1.0 + odds), contributions, missing }
+    }
+}
+
+/// A reading is only as trusted as its weakest required annotation: it scores one if every required
+/// type is satisfied and zero otherwise. Without required types every weighted type is required.
+pub struct MinimumOfRequiredEngine(pub ScoringPolicy);
+
+impl ScoringEngine for MinimumOfRequiredEngine {
+    fn score(&self, reading: &ReadingWrap, annotations: &[&AnnotationWrap]) -> Score {
+        let sensor = &reading.reading.id;
+        let weights = self.0.weights(sensor);
+        let mut required = self.0.required(sensor);
+        if required.is_empty() {
+            required = weights.iter().filter(|(_, weight)| **weight > 0.0).map(|(kind, _)| kind.clone()).collect();
+            required.sort();
+        }
+
+        let annotations = first_of_each_kind(annotations);
+        let missing = required.iter()
+            .filter(|kind| !annotations.iter().any(|ann| ann.annotation.kind.kind().eq(kind.as_str())))
+            .cloned()
+            .collect::<Vec<String>>();
+        let value = if missing.is_empty() && annotations.iter()
+            .filter(|ann| required.iter().any(|kind| kind.eq(ann.annotation.kind.kind())))
+            .all(|ann| ann.annotation.is_satisfied) { 1.0 } else { 0.0 };
+
+        let contributions = annotations.iter()
+            .map(|ann| {
+                let is_required = required.iter().any(|kind| kind.eq(ann.annotation.kind.kind()));
+                Contribution {
+                    kind: ann.annotation.kind.kind().to_string(),
+                    satisfied: ann.annotation.is_satisfied,
+                    weight: if is_required { 1.0 } else { 0.0 },
+                    contribution: if is_required && value > 0.0 { 1.0 / required.len() as f32 } else { 0.0 },
+                }
+            })
+            .collect();
+        Score { engine: EngineKind::MinimumOfRequired, value, contributions, missing }
+    }
+}
+
+/// Propagates trust along the hops a reading went through, from the device onwards. Each hop is
+/// trusted as much as the weight of its satisfied annotation types over its total weight, and a
+/// reading is trusted as much as the product of the trust of every hop on its path.
+pub struct GraphEngine(pub ScoringPolicy);
+
+impl ScoringEngine for GraphEngine {
+    fn score(&self, reading: &ReadingWrap, annotations: &[&AnnotationWrap]) -> Score {
+        let sensor = &reading.reading.id;
+        let weights = self.0.weights(sensor);
+        let missing = self.0.missing(sensor, annotations);
+        if !missing.is_empty() {
+            return Score::rejected(EngineKind::Graph, annotations, &weights, missing)
+        }
+
+        let annotations = first_of_each_kind(annotations);
+        let mut trust = 1.0_f32;
+        let mut contributions = Vec::new();
+        for hop in &self.0.hops {
+            let total = hop.iter().filter_map(|kind| weights.get(kind)).sum::<f32>();
+            if total <= 0.0 {
+                continue
+            }
+            // Trust that reached this hop, shared between the checks made at it
+            let upstream = trust;
+            let mut satisfied = 0.0;
+            for ann in annotations.iter().filter(|ann| hop.iter().any(|kind| kind.eq(ann.annotation.kind.kind()))) {
+                let weight = weights.get(ann.annotation.kind.kind()).copied().unwrap_or_default();
+                if ann.annotation.is_satisfied {
+                    satisfied += weight;
+                }
+                contributions.push(Contribution {
+                    kind: ann.annotation.kind.kind().to_string(),
+                    satisfied: ann.annotation.is_satisfied,
+                    weight,
+                    contribution: if ann.annotation.is_satisfied { upstream * weight / total } else { 0.0 },
+                });
+            }
+            trust *= satisfied / total;
+        }
+        Score { engine: EngineKind::Graph, value: trust, contributions, missing }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alvarium_annotator::Annotation;
+    use alvarium_annotator::constants::{AnnotationType, HashType};
+    use crate::SensorReading;
+    use super::*;
+
+    const SENSOR: &str = "Flow_Sensor_1";
+
+    fn reading() -> ReadingWrap {
+        ReadingWrap {
+            id: "reading".to_string(),
+            address: String::new(),
+            reading: SensorReading { id: SENSOR.to_string(), value: 42, timestamp: Utc::now() },
+            batched: false,
+            payload: None,
+        }
+    }
+
+    fn annotation(kind: &str, satisfied: bool) -> AnnotationWrap {
+        AnnotationWrap {
+            reading_id: "reading".to_string(),
+            reading_address: None,
+            verified: true,
+            annotation: Annotation::new(
+                "reading",
+                HashType("sha256".to_string()),
+                "host",
+                AnnotationType(kind.to_string()),
+                satisfied,
+            ),
+        }
+    }
+
+    fn policy(engine: EngineKind, weights: &[(&str, f32)]) -> ScoringPolicy {
+        ScoringPolicy {
+            engine,
+            weights: weights.iter().map(|(kind, weight)| (kind.to_string(), *weight)).collect(),
+            ..ScoringPolicy::default()
+        }
+    }
+
+    fn score_with(policy: ScoringPolicy, annotations: &[AnnotationWrap]) -> Score {
+        new_engine(policy).score(&reading(), &annotations.iter().collect::<Vec<&AnnotationWrap>>())
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-5, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn weighted_total_counts_satisfied_weight_once_per_kind() {
+        let policy = policy(EngineKind::Weighted, &[("pki", 3.0), ("tls", 1.0)]);
+        let annotations = [annotation("pki", true), annotation("tls", false), annotation("pki", false)];
+        let score = score_with(policy, &annotations);
+        assert_eq!(score.engine, EngineKind::Weighted);
+        assert_close(score.value, 0.75);
+        assert_eq!(score.contributions.len(), 2);
+        assert_close(score.contributions[0].contribution, 0.75);
+        assert_close(score.contributions[1].contribution, 0.0);
+    }
+
+    #[test]
+    fn weighted_total_uses_sensor_overrides() {
+        let mut policy = policy(EngineKind::Weighted, &[("pki", 3.0), ("tls", 1.0)]);
+        policy.sensors.insert(SENSOR.to_string(), SensorPolicy {
+            weights: HashMap::from([("tls".to_string(), 3.0)]),
+            required: None,
+        });
+        let score = score_with(policy, &[annotation("pki", false), annotation("tls", true)]);
+        assert_close(score.value, 0.5);
+    }
+
+    #[test]
+    fn bayesian_starts_from_the_prior() {
+        let mut policy = policy(EngineKind::Bayesian, &[("pki", 3.0)]);
+        policy.prior = 0.2;
+        let score = score_with(policy, &[]);
+        assert_eq!(score.engine, EngineKind::Bayesian);
+        assert_close(score.value, 0.2);
+        assert!(score.contributions.is_empty());
+    }
+
+    #[test]
+    fn bayesian_updates_the_odds_with_each_annotation() {
+        let policy = policy(EngineKind::Bayesian, &[("pki", 3.0), ("tls", 1.0)]);
+        // Even odds, multiplied by 4 for the satisfied pki check then divided by 2 for the failed tls one
+        let score = score_with(policy, &[annotation("pki", true), annotation("tls", false)]);
+        assert_close(score.value, 2.0 / 3.0);
+        assert_close(score.contributions[0].contribution, 0.8 - 0.5);
+        assert_close(score.contributions[1].contribution, 2.0 / 3.0 - 0.8);
+    }
+
+    #[test]
+    fn minimum_of_required_only_checks_required_kinds() {
+        let mut policy = policy(EngineKind::MinimumOfRequired, &[("pki", 3.0), ("tls", 1.0)]);
+        policy.required = vec!["pki".to_string()];
+
+        let score = score_with(policy.clone(), &[annotation("pki", true), annotation("tls", false)]);
+        assert_eq!(score.engine, EngineKind::MinimumOfRequired);
+        assert_close(score.value, 1.0);
+        assert!(score.missing.is_empty());
+
+        let score = score_with(policy, &[annotation("pki", false), annotation("tls", true)]);
+        assert_close(score.value, 0.0);
+    }
+
+    #[test]
+    fn minimum_of_required_requires_every_weighted_kind_by_default() {
+        let policy = policy(EngineKind::MinimumOfRequired, &[("pki", 3.0), ("tls", 1.0), ("source", 0.0)]);
+
+        let score = score_with(policy.clone(), &[annotation("pki", true), annotation("tls", true)]);
+        assert_close(score.value, 1.0);
+
+        let score = score_with(policy.clone(), &[annotation("pki", true), annotation("tls", false)]);
+        assert_close(score.value, 0.0);
+
+        let score = score_with(policy, &[annotation("pki", true)]);
+        assert_close(score.value, 0.0);
+        assert_eq!(score.missing, vec!["tls".to_string()]);
+    }
+
+    #[test]
+    fn graph_multiplies_the_trust_of_each_hop() {
+        let policy = policy(EngineKind::Graph, &[("pki", 3.0), ("source", 1.0), ("tls", 2.0), ("threshold", 2.0)]);
+
+        let annotations = [
+            annotation("pki", true),
+            annotation("source", false),
+            annotation("tls", true),
+            annotation("threshold", true),
+        ];
+        let score = score_with(policy.clone(), &annotations);
+        assert_eq!(score.engine, EngineKind::Graph);
+        assert_close(score.value, 0.75);
+
+        let annotations = [
+            annotation("pki", true),
+            annotation("source", false),
+            annotation("tls", true),
+            annotation("threshold", false),
+        ];
+        let score = score_with(policy, &annotations);
+        assert_close(score.value, 0.75 * 0.5);
+        // The checks of the second hop share the trust that reached it
+        let tls = score.contributions.iter().find(|c| c.kind.eq("tls")).unwrap();
+        assert_close(tls.contribution, 0.75 * 0.5);
+    }
+
+    #[test]
+    fn graph_skips_hops_without_weight() {
+        let policy = policy(EngineKind::Graph, &[("pki", 1.0)]);
+        let score = score_with(policy, &[annotation("pki", true)]);
+        assert_close(score.value, 1.0);
+    }
+
+    #[test]
+    fn readings_missing_required_kinds_are_rejected() {
+        for engine in [EngineKind::Weighted, EngineKind::Bayesian, EngineKind::MinimumOfRequired, EngineKind::Graph] {
+            let mut policy = policy(engine, &[("pki", 3.0), ("tls", 1.0)]);
+            policy.required = vec!["pki".to_string()];
+            let score = score_with(policy, &[annotation("tls", true)]);
+            assert_eq!(score.engine, engine);
+            assert_close(score.value, 0.0);
+            assert_eq!(score.missing, vec!["pki".to_string()]);
+            assert!(score.contributions.iter().all(|c| c.contribution == 0.0));
+        }
+    }
+
+    #[test]
+    fn version_follows_the_policy_content() {
+        let policy = ScoringPolicy::default();
+        assert_eq!(policy.version(), ScoringPolicy::default().version());
+        assert_eq!(policy.version().len(), 12);
+
+        let mut changed = ScoringPolicy::default();
+        changed.weights.insert("pki".to_string(), 1.0);
+        assert_ne!(policy.version(), changed.version());
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use crypto::signatures::ed25519::SecretKey;
+use serde::Serialize;
+use streams::{Address, User};
+use streams::id::{Ed25519, Psk};
+use streams::transport::utangle::Client;
+use crate::errors::{Error, Result};
+use crate::records::Records;
+use crate::store::{Store, LEGACY_ANNOTATIONS_PATH, LEGACY_READINGS_PATH, STORE_PATH};
+use crate::verification::AnnotationVerifier;
+use crate::{NODE_URL, PROVIDER_URL};
+
+/// Backup of the subscriber stream user, holding the position of the subscriber in the stream
+pub const USER_PATH: &'static str = "user.bin";
+
+/// What became of a piece of the subscriber state on start
+#[derive(Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// Read back as it was saved
+    Restored,
+    /// Not found, started afresh
+    Missing,
+    /// Found but unreadable, set aside and started afresh
+    Corrupt,
+    /// Readable but discarded so that the stream is replayed from the announcement
+    Replayed,
+}
+
+impl Display for Recovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recovery::Restored => write!(f, "restored"),
+            Recovery::Missing => write!(f, "missing"),
+            Recovery::Corrupt => write!(f, "corrupt"),
+            Recovery::Replayed => write!(f, "replayed"),
+        }
+    }
+}
+
+/// Subscriber state as it was recovered on start
+pub struct State {
+    pub user: User<Client>,
+    pub store: Store,
+    pub records: Records,
+}
+
+/// Restore the stream user, the store and the records kept in memory, each one independently of the
+/// others. When the store lost readings or annotations the stream user is rebuilt from the
+/// announcement, so that the stream is replayed from its start and the lost records received again.
+pub async fn restore(
+    announcement: Address,
+    psk: Psk,
+    topics: &[String],
+    verifier: &AnnotationVerifier,
+    mut records: Records,
+) -> State {
+    let (store, store_recovery) = open_store();
+    // Readings and annotations are persisted as they arrive, the json files of earlier versions are
+    // imported the first time the store is opened
+    let failed = store.migrate_legacy_files().unwrap_or_else(|e| {
+        error!("Failed to import the legacy files: {}", e);
+        vec![LEGACY_READINGS_PATH.to_string(), LEGACY_ANNOTATIONS_PATH.to_string()]
+    });
+
+    // Annotations are restored first so that readings are scored as they are restored. A store that
+    // cannot be read through counts as corrupt, what it holds is received again from the stream.
+    let mut unreadable = false;
+    let (annotations, skipped_annotations) = store.recover_annotations().unwrap_or_else(|e| {
+        error!("Failed to read the stored annotations: {}", e);
+        unreadable = true;
+        (Vec::new(), 0)
+    });
+    let restored_annotations = annotations.len();
+    for mut annotation in annotations {
+        // The publisher key may have changed since the annotations were stored
+        annotation.verified = verifier.verify(&annotation.annotation);
+        records.push_annotation(annotation);
+    }
+    let (readings, skipped_readings) = store.recover_readings().unwrap_or_else(|e| {
+        error!("Failed to read the stored readings: {}", e);
+        unreadable = true;
+        (Vec::new(), 0)
+    });
+    let restored_readings = readings.len();
+    readings.into_iter().for_each(|reading| records.push_reading(reading));
+    records.prune(chrono::Utc::now());
+
+    // A store created from scratch only lost records if nothing could be imported into it
+    let empty = restored_readings == 0 && restored_annotations == 0;
+    let lost = store_recovery == Recovery::Corrupt
+        || unreadable
+        || (store_recovery == Recovery::Missing && empty)
+        || !failed.is_empty()
+        || skipped_readings > 0
+        || skipped_annotations > 0;
+    let (user, user_recovery) = match read_user().await {
+        Ok(_) if lost => {
+            warn!("Local records were lost, replaying the stream from the announcement to rebuild them");
+            (new_user(announcement, psk, topics).await, Recovery::Replayed)
+        },
+        Ok(user) => (Ok(user), Recovery::Restored),
+        Err(recovery) => (new_user(announcement, psk, topics).await, recovery),
+    };
+    // Without the announcement there is no stream to follow
+    let user = user.expect("Failed to receive the stream announcement");
+
+    info!(
+        "Recovered state: user {}, store {}, {} readings ({} unreadable) and {} annotations ({} unreadable), {} retained",
+        user_recovery,
+        store_recovery,
+        restored_readings,
+        skipped_readings,
+        restored_annotations,
+        skipped_annotations,
+        records.reading_count(),
+    );
+    if !failed.is_empty() {
+        warn!("Could not import {}", failed.join(", "));
+    }
+
+    // Scores computed from now on are kept, the history is scored in the background
+    let records = records.persisting(store.clone());
+    State { user, store, records }
+}
+
+/// Open the store, setting it aside and starting an empty one if it cannot be opened. If no store can
+/// be opened on disk the records are only kept in memory until the next start.
+fn open_store() -> (Store, Recovery) {
+    let existed = Path::new(STORE_PATH).exists();
+    match Store::open(STORE_PATH) {
+        Ok(store) if existed => return (store, Recovery::Restored),
+        Ok(store) => return (store, Recovery::Missing),
+        Err(e) => warn!("Failed to open {}, setting it aside: {}", STORE_PATH, e),
+    }
+
+    let reopened = fs::rename(STORE_PATH, format!("{}.corrupt", STORE_PATH))
+        .map_err(|e| e.to_string())
+        .and_then(|_| Store::open(STORE_PATH).map_err(|e| e.to_string()));
+    match reopened {
+        Ok(store) => (store, Recovery::Corrupt),
+        Err(e) => {
+            error!("Failed to start a new {}, keeping records in memory only: {}", STORE_PATH, e);
+            // Nothing is written to disk, so only an in memory database can fail here
+            (Store::temporary().expect("in memory store"), Recovery::Corrupt)
+        }
+    }
+}
+
+/// Stream user restored from its backup, or what became of the backup if there is none to restore
+async fn read_user() -> std::result::Result<User<Client>, Recovery> {
+    let bytes = fs::read(USER_PATH).map_err(|_| Recovery::Missing)?;
+    match User::restore(bytes, "password", Client::new(NODE_URL)).await {
+        Ok(user) => Ok(user),
+        Err(e) => {
+            warn!("Failed to restore {}, setting it aside: {}", USER_PATH, e);
+            if let Err(e) = fs::rename(USER_PATH, format!("{}.corrupt", USER_PATH)) {
+                // The unreadable backup is overwritten by the one of the new user instead
+                warn!("Failed to set {} aside: {}", USER_PATH, e);
+            }
+            Err(Recovery::Corrupt)
+        }
+    }
+}
+
+/// Stream user subscribed from the announcement, which receives every message of the stream again. It
+/// subscribes to the given topics with an identity of its own, so that it keeps access to them when the
+/// publisher rotates the pre shared key. The backup is replaced by the new user once it received the
+/// announcement.
+pub async fn new_user(announcement: Address, psk: Psk, topics: &[String]) -> Result<User<Client>> {
+    let mut user = User::builder()
+        .with_identity(Ed25519::new(SecretKey::generate().unwrap()))
+        .with_transport(Client::new(NODE_URL))
+        .with_psk(psk.to_pskid(), psk)
+        .build();
+    user.receive_message(announcement).await.map_err(|e| Error::StreamError(e.to_string()))?;
+    if let Err(e) = subscribe(&mut user, topics).await {
+        warn!("Failed to subscribe, only messages keyloaded with the pre shared key can be read: {}", e);
+    }
+    match user.backup("password").await {
+        Ok(backup) => if let Err(e) = fs::write(USER_PATH, backup) {
+            error!("Failed to write {}: {}", USER_PATH, e);
+        },
+        Err(e) => error!("Failed to back up the user: {}", e),
+    }
+    info!("User received announcement");
+    Ok(user)
+}
+
+/// Send a subscription message and ask the publisher to keyload it on the topics
+async fn subscribe(user: &mut User<Client>, topics: &[String]) -> std::result::Result<(), String> {
+    #[derive(Serialize)]
+    struct SubscriptionRequest<'a> {
+        address: String,
+        identifier: String,
+        #[serde(rename = "idType")]
+        id_type: u8,
+        topics: &'a [String],
+    }
+
+    let subscription = user.subscribe().await.map_err(|e| e.to_string())?;
+    let request = SubscriptionRequest {
+        address: subscription.address().to_string(),
+        identifier: user.identifier().map(|id| id.to_string()).unwrap_or_default(),
+        id_type: 0,
+        topics,
+    };
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscribe", PROVIDER_URL))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&request).map_err(|e| e.to_string())?)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("publisher answered {}", response.status()))
+    }
+    info!("Subscribed to {}", topics.join(", "));
+    Ok(())
+}
@@ -0,0 +1,332 @@
+use std::fs;
+use alvarium_annotator::constants::HashType;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::errors::{Error, Result};
+use crate::scoring::{PolicyRecord, ScoreRecord};
+use crate::{AnnotationWrap, ReadingWrap, SensorKeyRegistry};
+
+/// Directory of the embedded database holding the readings and annotations
+pub const STORE_PATH: &'static str = "subscriber.db";
+/// Files the readings and annotations were persisted to before the database existed
+pub const LEGACY_READINGS_PATH: &'static str = "readings.bin";
+pub const LEGACY_ANNOTATIONS_PATH: &'static str = "annotations.bin";
+
+/// Separates the components of index keys
+const SEPARATOR: u8 = 0;
+
+/// Readings, annotations and sensor key registries persisted as they are received, along with indexes
+/// of readings by sensor and timestamp and of annotations by key, and the scores of readings under each
+/// version of the scoring policy
+#[derive(Clone)]
+pub struct Store {
+    db: sled::Db,
+    /// Reading id -> reading
+    readings: sled::Tree,
+    /// Annotation id -> annotation
+    annotations: sled::Tree,
+    /// Sensor id, timestamp, reading id -> empty
+    readings_by_sensor: sled::Tree,
+    /// Annotation key, annotation id -> empty
+    annotations_by_key: sled::Tree,
+    /// Hash type name -> empty, for every hash type annotations were seen with
+    hash_types: sled::Tree,
+    /// Reading id, policy version -> score
+    scores: sled::Tree,
+    /// Sensor id, policy version, timestamp, reading id -> key of the score
+    scores_by_sensor: sled::Tree,
+    /// Policy version -> policy
+    policies: sled::Tree,
+    /// Timestamp -> sensor key registry published by then
+    sensor_keys: sled::Tree,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self> {
+        Store::with_db(sled::open(path)?)
+    }
+
+    /// Store kept in memory only, for when the database on disk cannot be used at all
+    pub fn temporary() -> Result<Self> {
+        Store::with_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn with_db(db: sled::Db) -> Result<Self> {
+        Ok(Store {
+            readings: db.open_tree("readings")?,
+            annotations: db.open_tree("annotations")?,
+            readings_by_sensor: db.open_tree("readings_by_sensor")?,
+            annotations_by_key: db.open_tree("annotations_by_key")?,
+            hash_types: db.open_tree("hash_types")?,
+            scores: db.open_tree("scores")?,
+            scores_by_sensor: db.open_tree("scores_by_sensor")?,
+            policies: db.open_tree("policies")?,
+            sensor_keys: db.open_tree("sensor_keys")?,
+            db,
+        })
+    }
+
+    pub fn insert_reading(&self, reading: &ReadingWrap) -> Result<()> {
+        self.readings.insert(reading.id.as_bytes(), encode(reading)?)?;
+        let timestamp = reading.reading.timestamp.timestamp_millis().max(0) as u64;
+        let key = index_key(&[reading.reading.id.as_bytes(), &timestamp.to_be_bytes(), reading.id.as_bytes()]);
+        self.readings_by_sensor.insert(key, &[])?;
+        Ok(())
+    }
+
+    pub fn insert_annotation(&self, annotation: &AnnotationWrap) -> Result<()> {
+        self.annotations.insert(annotation.annotation.id.as_bytes(), encode(annotation)?)?;
+        let key = index_key(&[annotation.annotation.key.as_bytes(), annotation.annotation.id.as_bytes()]);
+        self.annotations_by_key.insert(key, &[])?;
+        self.hash_types.insert(annotation.annotation.hash.0.as_bytes(), &[])?;
+        Ok(())
+    }
+
+    pub fn insert_score(&self, record: &ScoreRecord) -> Result<()> {
+        let key = index_key(&[record.reading_id.as_bytes(), record.version.as_bytes()]);
+        self.scores.insert(&key, encode(record)?)?;
+        let timestamp = record.timestamp.timestamp_millis().max(0) as u64;
+        let index = index_key(&[
+            record.sensor.as_bytes(),
+            record.version.as_bytes(),
+            &timestamp.to_be_bytes(),
+            record.reading_id.as_bytes(),
+        ]);
+        self.scores_by_sensor.insert(index, key)?;
+        Ok(())
+    }
+
+    pub fn insert_policy(&self, record: &PolicyRecord) -> Result<()> {
+        self.policies.insert(record.version.as_bytes(), encode(record)?)?;
+        Ok(())
+    }
+
+    pub fn insert_sensor_keys(&self, registry: &SensorKeyRegistry) -> Result<()> {
+        let timestamp = registry.timestamp.timestamp_millis().max(0) as u64;
+        self.sensor_keys.insert(timestamp.to_be_bytes(), encode(registry)?)?;
+        Ok(())
+    }
+
+    /// Every sensor key registry received, oldest first
+    pub fn sensor_keys(&self) -> Result<Vec<SensorKeyRegistry>> {
+        self.sensor_keys.iter().values()
+            .map(|value| decode(&value?))
+            .collect()
+    }
+
+    pub fn policy(&self, version: &str) -> Result<Option<PolicyRecord>> {
+        self.policies.get(version.as_bytes())?.map(|value| decode(&value)).transpose()
+    }
+
+    /// Every version of the scoring policy readings were scored under, oldest first
+    pub fn policies(&self) -> Result<Vec<PolicyRecord>> {
+        let mut policies = self.policies.iter().values()
+            .map(|value| decode(&value?))
+            .collect::<Result<Vec<PolicyRecord>>>()?;
+        policies.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(policies)
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn annotation_count(&self) -> usize {
+        self.annotations.len()
+    }
+
+    /// Remove every reading, annotation and sensor key registry, along with their indexes. The scores
+    /// are kept, readings are identified by the hash of their payload so their score history still
+    /// applies when they are received again.
+    pub fn clear(&self) -> Result<()> {
+        let trees = [
+            &self.readings,
+            &self.annotations,
+            &self.readings_by_sensor,
+            &self.annotations_by_key,
+            &self.hash_types,
+            &self.sensor_keys,
+        ];
+        for tree in trees {
+            tree.clear()?;
+        }
+        self.flush()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Readings that can still be read, along with the number of unreadable ones that were skipped
+    pub fn recover_readings(&self) -> Result<(Vec<ReadingWrap>, usize)> {
+        recover(&self.readings)
+    }
+
+    /// Annotations that can still be read, along with the number of unreadable ones that were skipped
+    pub fn recover_annotations(&self) -> Result<(Vec<AnnotationWrap>, usize)> {
+        recover(&self.annotations)
+    }
+
+    /// Readings of a sensor, oldest first
+    pub fn readings_by_sensor(&self, sensor: &str) -> Result<Vec<ReadingWrap>> {
+        let ids = self.readings_by_sensor.scan_prefix(index_key(&[sensor.as_bytes()]))
+            .keys()
+            .map(|key| key.map(|key| last_component(&key)))
+            .collect::<std::result::Result<Vec<Vec<u8>>, sled::Error>>()?;
+        lookup(&self.readings, ids)
+    }
+
+    /// Sensors with stored readings
+    pub fn sensors(&self) -> Result<Vec<String>> {
+        let mut sensors: Vec<String> = Vec::new();
+        for key in self.readings_by_sensor.iter().keys() {
+            let key = key?;
+            let end = key.iter().position(|b| *b == SEPARATOR).unwrap_or(key.len());
+            let sensor = String::from_utf8_lossy(&key[..end]).to_string();
+            // Keys are sorted, so the readings of a sensor are next to each other
+            if sensors.last() != Some(&sensor) {
+                sensors.push(sensor);
+            }
+        }
+        Ok(sensors)
+    }
+
+    /// Annotations with the given key, that is of the readings whose payload hashes to it
+    pub fn annotations_by_key(&self, key: &str) -> Result<Vec<AnnotationWrap>> {
+        let ids = self.annotations_by_key.scan_prefix(index_key(&[key.as_bytes()]))
+            .keys()
+            .map(|key| key.map(|key| last_component(&key)))
+            .collect::<std::result::Result<Vec<Vec<u8>>, sled::Error>>()?;
+        lookup(&self.annotations, ids)
+    }
+
+    /// Hash types stored annotations were derived with
+    pub fn hash_types(&self) -> Result<Vec<HashType>> {
+        self.hash_types.iter().keys()
+            .map(|key| Ok(HashType(String::from_utf8_lossy(&key?).to_string())))
+            .collect()
+    }
+
+    /// Scores of a reading under every version of the scoring policy
+    pub fn scores_for_reading(&self, reading_id: &str) -> Result<Vec<ScoreRecord>> {
+        self.scores.scan_prefix(index_key(&[reading_id.as_bytes()])).values()
+            .map(|value| decode(&value?))
+            .collect()
+    }
+
+    /// Scores of the readings of a sensor under a version of the scoring policy, oldest first
+    pub fn scores_by_sensor(&self, sensor: &str, version: &str) -> Result<Vec<ScoreRecord>> {
+        let keys = self.scores_by_sensor.scan_prefix(index_key(&[sensor.as_bytes(), version.as_bytes()]))
+            .values()
+            .map(|key| key.map(|key| key.to_vec()))
+            .collect::<std::result::Result<Vec<Vec<u8>>, sled::Error>>()?;
+        lookup(&self.scores, keys)
+    }
+
+    /// Import the readings and annotations of the json files used before the database existed, once.
+    /// The files are renamed after the import so that they are not imported again, and files that cannot
+    /// be parsed are set aside with a `.corrupt` suffix. Returns the files that could not be imported.
+    pub fn migrate_legacy_files(&self) -> Result<Vec<String>> {
+        let mut failed = Vec::new();
+        match read_legacy::<ReadingWrap>(LEGACY_READINGS_PATH) {
+            Some(Ok(readings)) => {
+                for reading in &readings {
+                    self.insert_reading(reading)?;
+                }
+                set_aside(LEGACY_READINGS_PATH, "migrated")?;
+                info!("Migrated {} readings from {}", readings.len(), LEGACY_READINGS_PATH);
+            },
+            Some(Err(e)) => {
+                warn!("Failed to parse {}, setting it aside: {}", LEGACY_READINGS_PATH, e);
+                set_aside(LEGACY_READINGS_PATH, "corrupt")?;
+                failed.push(LEGACY_READINGS_PATH.to_string());
+            },
+            None => (),
+        }
+        match read_legacy::<AnnotationWrap>(LEGACY_ANNOTATIONS_PATH) {
+            Some(Ok(annotations)) => {
+                for annotation in &annotations {
+                    self.insert_annotation(annotation)?;
+                }
+                set_aside(LEGACY_ANNOTATIONS_PATH, "migrated")?;
+                info!("Migrated {} annotations from {}", annotations.len(), LEGACY_ANNOTATIONS_PATH);
+            },
+            Some(Err(e)) => {
+                warn!("Failed to parse {}, setting it aside: {}", LEGACY_ANNOTATIONS_PATH, e);
+                set_aside(LEGACY_ANNOTATIONS_PATH, "corrupt")?;
+                failed.push(LEGACY_ANNOTATIONS_PATH.to_string());
+            },
+            None => (),
+        }
+        self.db.flush()?;
+        Ok(failed)
+    }
+}
+
+/// Records of a legacy file, or `None` if there is no such file
+fn read_legacy<T: DeserializeOwned>(path: &str) -> Option<Result<Vec<T>>> {
+    let bytes = fs::read(path).ok()?;
+    Some(serde_json::from_slice(&bytes).map_err(|e| Error::SerialisationError(e.to_string())))
+}
+
+/// Rename a legacy file with the given suffix so that it is not read again
+fn set_aside(path: &str, suffix: &str) -> Result<()> {
+    fs::rename(path, format!("{}.{}", path, suffix))
+        .map_err(|e| Error::StoreMigrationError(e.to_string()))
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| Error::SerialisationError(e.to_string()))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    serde_json::from_slice(bytes).map_err(|e| Error::SerialisationError(e.to_string()))
+}
+
+/// Join the components of an index key, each one followed by the separator so that scanning the
+/// prefix of a component does not match longer components starting with it
+fn index_key(components: &[&[u8]]) -> Vec<u8> {
+    let mut key = Vec::new();
+    for component in components {
+        key.extend_from_slice(component);
+        key.push(SEPARATOR);
+    }
+    key
+}
+
+/// The record id at the end of an index key
+fn last_component(key: &[u8]) -> Vec<u8> {
+    let key = &key[..key.len().saturating_sub(1)];
+    match key.iter().rposition(|b| *b == SEPARATOR) {
+        Some(position) => key[position + 1..].to_vec(),
+        None => key.to_vec(),
+    }
+}
+
+fn lookup<T: DeserializeOwned>(tree: &sled::Tree, ids: Vec<Vec<u8>>) -> Result<Vec<T>> {
+    let mut records = Vec::new();
+    for id in ids {
+        if let Some(value) = tree.get(id)? {
+            records.push(decode(&value)?);
+        }
+    }
+    Ok(records)
+}
+
+/// Decode every record of a tree, skipping the ones that cannot be decoded
+fn recover<T: DeserializeOwned>(tree: &sled::Tree) -> Result<(Vec<T>, usize)> {
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for entry in tree.iter() {
+        let (id, value) = entry?;
+        match decode(&value) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                warn!("Skipping unreadable record {}: {}", String::from_utf8_lossy(&id), e);
+                skipped += 1;
+            }
+        }
+    }
+    Ok((records, skipped))
+}
@@ -0,0 +1,102 @@
+use alvarium_annotator::Annotation;
+use crypto::signatures::ed25519::{PublicKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+
+/// Checks that annotations were signed by the publisher, so that other writers to the channel cannot
+/// forge trust in a reading
+pub struct AnnotationVerifier {
+    public_key: Option<PublicKey>,
+}
+
+impl AnnotationVerifier {
+    /// Verifier for the hex encoded ed25519 public key of the publisher. An invalid key leaves every
+    /// annotation unverified.
+    pub fn new(public_key: &str) -> Self {
+        let public_key = hex::decode(public_key.trim()).ok()
+            .and_then(|bytes| <[u8; PUBLIC_KEY_LENGTH]>::try_from(bytes).ok())
+            .and_then(|bytes| PublicKey::try_from_bytes(bytes).ok());
+        if public_key.is_none() {
+            warn!("No valid publisher public key, annotations cannot be verified");
+        }
+        AnnotationVerifier { public_key }
+    }
+
+    /// Whether the annotation signature was produced by the publisher key. Annotations are signed over
+    /// their json serialisation without a signature.
+    pub fn verify(&self, annotation: &Annotation) -> bool {
+        let public_key = match &self.public_key {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+        let signature = match hex::decode(&annotation.signature).ok()
+            .and_then(|bytes| <[u8; SIGNATURE_LENGTH]>::try_from(bytes).ok()) {
+            Some(bytes) => Signature::from_bytes(bytes),
+            None => return false,
+        };
+
+        let mut unsigned = annotation.clone();
+        unsigned.signature = String::new();
+        match serde_json::to_vec(&unsigned) {
+            Ok(content) => public_key.verify(&signature, &content),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alvarium_annotator::constants::{AnnotationType, HashType};
+    use crypto::signatures::ed25519::SecretKey;
+    use super::*;
+
+    /// Annotation signed the way the sdk signs them, over its json serialisation with an empty signature
+    fn signed(key: &SecretKey, satisfied: bool) -> Annotation {
+        let mut annotation = Annotation::new(
+            "key",
+            HashType("sha256".to_string()),
+            "host",
+            AnnotationType("pki".to_string()),
+            satisfied,
+        );
+        let content = serde_json::to_vec(&annotation).unwrap();
+        annotation.signature = hex::encode(key.sign(&content).to_bytes());
+        annotation
+    }
+
+    fn verifier(key: &SecretKey) -> AnnotationVerifier {
+        AnnotationVerifier::new(&hex::encode(key.public_key().as_slice()))
+    }
+
+    #[test]
+    fn annotations_signed_by_the_publisher_verify() {
+        let key = SecretKey::generate().unwrap();
+        assert!(verifier(&key).verify(&signed(&key, true)));
+        assert!(verifier(&key).verify(&signed(&key, false)));
+    }
+
+    #[test]
+    fn tampered_annotations_do_not_verify() {
+        let key = SecretKey::generate().unwrap();
+        let mut annotation = signed(&key, false);
+        annotation.is_satisfied = true;
+        assert!(!verifier(&key).verify(&annotation));
+    }
+
+    #[test]
+    fn annotations_signed_by_another_key_do_not_verify() {
+        let key = SecretKey::generate().unwrap();
+        let other = SecretKey::generate().unwrap();
+        assert!(!verifier(&other).verify(&signed(&key, true)));
+    }
+
+    #[test]
+    fn malformed_keys_and_signatures_do_not_verify() {
+        let key = SecretKey::generate().unwrap();
+        let annotation = signed(&key, true);
+        assert!(!AnnotationVerifier::new("not a key").verify(&annotation));
+        assert!(!AnnotationVerifier::new("").verify(&annotation));
+
+        let mut truncated = annotation.clone();
+        truncated.signature.truncate(10);
+        assert!(!verifier(&key).verify(&truncated));
+    }
+}